@@ -0,0 +1,165 @@
+pub mod additive {
+
+    use crate::source::source::SoundSource;
+
+    use std::f64::consts::PI;
+
+    // One entry of a harmonic table, harmonics[n - 1] describes harmonic n
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Harmonic {
+        pub amplitude: f64,
+        pub phase: f64, // Offset in cycles (0.0 .. 1.0)
+    }
+
+    // Sum of sine harmonics of `frequency`. Harmonics at or above Nyquist are never rendered,
+    // so it stays alias free at any pitch, and each harmonic costs one multiply-add per sample:
+    // sines are produced by the recursion sin(x + w) = 2 cos(w) sin(x) - sin(x - w), restarted
+    // from the exact phase at every block.
+    #[derive(Clone, Debug)]
+    pub struct AdditiveOscillator {
+        pub frequency: f64, // Hz
+        pub amplitude: f64,
+        harmonics: Vec<Harmonic>,
+        max_harmonics: usize, // User limit, lowered further by Nyquist
+        phase: f64,           // Of the fundamental, 0.0 .. 1.0
+    }
+
+    impl AdditiveOscillator {
+        pub fn new(harmonics: Vec<Harmonic>, frequency: f64, amplitude: f64) -> Self {
+            return Self {
+                frequency: frequency,
+                amplitude: amplitude,
+                harmonics: harmonics,
+                max_harmonics: usize::MAX,
+                phase: 0.0,
+            };
+        }
+
+        // Harmonic table from a function of the harmonic number (1, 2, 3...)
+        pub fn from_fn(count: usize, harmonic: impl Fn(u32) -> Harmonic, frequency: f64, amplitude: f64) -> Self {
+            let harmonics = (1 ..= count as u32).map(harmonic).collect();
+            return Self::new(harmonics, frequency, amplitude);
+        }
+
+        // Same shape as Waveform::SawAnalog with as many harmonics as asked for
+        pub fn saw(count: usize, frequency: f64, amplitude: f64) -> Self {
+            return Self::from_fn(count, |n| Harmonic { amplitude: 2.0 / (PI * n as f64), phase: 0.0 }, frequency, amplitude);
+        }
+
+        pub fn square(count: usize, frequency: f64, amplitude: f64) -> Self {
+            return Self::from_fn(count, |n| Harmonic {
+                amplitude: if n % 2 == 1 { 4.0 / (PI * n as f64) } else { 0.0 },
+                phase: 0.0,
+            }, frequency, amplitude);
+        }
+
+        pub fn triangle(count: usize, frequency: f64, amplitude: f64) -> Self {
+            return Self::from_fn(count, |n| Harmonic {
+                amplitude: if n % 2 == 1 { 8.0 / (PI * PI * (n * n) as f64) } else { 0.0 },
+                phase: if n % 4 == 3 { 0.5 } else { 0.0 }, // Alternating signs
+            }, frequency, amplitude);
+        }
+
+        pub fn harmonics(&self) -> &[Harmonic] {
+            return &self.harmonics;
+        }
+
+        // Replaces the table, the phase of the fundamental carries on
+        pub fn set_harmonics(&mut self, harmonics: Vec<Harmonic>) {
+            self.harmonics = harmonics;
+        }
+
+        // Renders at most `max_harmonics` of the table, fewer sound cheaper
+        pub fn set_max_harmonics(&mut self, max_harmonics: usize) {
+            self.max_harmonics = max_harmonics;
+        }
+
+        // Harmonics rendered at the current frequency: below Nyquist and within both limits.
+        // None for 0Hz, NaN or infinity.
+        pub fn harmonic_limit(&self, sample_rate: u32) -> usize {
+            if self.frequency == 0.0 || !self.frequency.is_finite() {
+                return 0;
+            }
+            let nyquist = sample_rate as f64 / 2.0;
+            let below_nyquist = (f64::ceil(nyquist / f64::abs(self.frequency)) as usize).saturating_sub(1);
+            return below_nyquist.min(self.max_harmonics).min(self.harmonics.len());
+        }
+
+        // Fills `out` (mono) with the next out.len() samples
+        pub fn render(&mut self, out: &mut [f32], sample_rate: u32) {
+            out.iter_mut().for_each(|sample| *sample = 0.0);
+            self.render_add(out, sample_rate);
+        }
+
+        // Like render() but adds to what is in `out`
+        pub fn render_add(&mut self, out: &mut [f32], sample_rate: u32) {
+            let dt = self.frequency / sample_rate as f64;
+            let limit = self.harmonic_limit(sample_rate);
+
+            for (index, harmonic) in self.harmonics[.. limit].iter().enumerate() {
+                if harmonic.amplitude == 0.0 {
+                    continue;
+                }
+                let n = (index + 1) as f64;
+                let step = 2.0 * PI * n * dt;
+                let start = 2.0 * PI * (n * self.phase + harmonic.phase);
+                let gain = self.amplitude * harmonic.amplitude;
+
+                let coefficient = 2.0 * f64::cos(step);
+                let mut previous = f64::sin(start - step);
+                let mut before_previous = f64::sin(start - 2.0 * step);
+                for sample in out.iter_mut() {
+                    let current = coefficient * previous - before_previous;
+                    *sample += (gain * current) as f32;
+                    before_previous = previous;
+                    previous = current;
+                }
+            }
+
+            // A NaN phase would stick after the frequency is valid again
+            if dt.is_finite() {
+                self.phase = (self.phase + dt * out.len() as f64).rem_euclid(1.0);
+            }
+        }
+    }
+
+    // Plays the harmonics of the note frequency
+    impl SoundSource for AdditiveOscillator {
+        fn generate(&mut self, out: &mut [f32], frequency: f64, sample_rate: u32) {
+            self.frequency = frequency;
+            self.render(out, sample_rate);
+        }
+
+        fn restart(&mut self) {
+            self.phase = 0.0;
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn harmonic_limit_stays_below_nyquist() {
+            let mut oscillator = AdditiveOscillator::saw(64, 440.0, 1.0);
+            assert_eq!(oscillator.harmonic_limit(44100), 50);
+            oscillator.frequency = 22050.0;
+            assert_eq!(oscillator.harmonic_limit(44100), 0);
+            oscillator.frequency = 1e-300;
+            assert_eq!(oscillator.harmonic_limit(44100), 64);
+        }
+
+        #[test]
+        fn no_harmonics_without_a_finite_frequency() {
+            for frequency in [0.0, f64::NAN, f64::INFINITY, f64::NEG_INFINITY].iter() {
+                let mut oscillator = AdditiveOscillator::saw(64, *frequency, 1.0);
+                assert_eq!(oscillator.harmonic_limit(44100), 0);
+
+                let mut out = vec![1.0; 64];
+                oscillator.render(&mut out, 44100);
+                assert!(out.iter().all(|sample| *sample == 0.0));
+                assert_eq!(oscillator.phase, 0.0);
+            }
+        }
+    }
+}
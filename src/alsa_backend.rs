@@ -0,0 +1,215 @@
+pub mod alsa_backend {
+
+    extern crate alsa;
+
+    use crate::backend::backend::{AudioBackend, BlockSignal, DeviceInfo, SampleFormat, StreamFormat, STANDARD_SAMPLE_RATES};
+    use crate::error::error::NoiseError;
+
+    use alsa::pcm::{Access, Format, HwParams, PCM};
+    use alsa::{Direction, ValueOr};
+
+    use std::sync::Arc;
+
+    // Blocking ALSA PCM playback. Device names are regular ALSA PCM names
+    // ("default", "hw:0,0", "plughw:0,0", ...), enumerate() lists the ones found on this machine.
+    pub struct AlsaBackend {
+        pcm: Option<PCM>,
+        signal: Option<Arc<BlockSignal>>,
+    }
+
+    impl AlsaBackend {
+        pub fn new() -> Self {
+            return Self {
+                pcm: None,
+                signal: None,
+            };
+        }
+
+        fn open_pcm(name: &str, format: &StreamFormat) -> alsa::Result<PCM> {
+            let pcm = PCM::new(name, Direction::Playback, false)?;
+            {
+                let frames_per_block = (format.block_samples / format.channels) as alsa::pcm::Frames;
+
+                let hwp = HwParams::any(&pcm)?;
+                hwp.set_access(Access::RWInterleaved)?;
+                hwp.set_format(Self::alsa_format(format.sample_format))?;
+                hwp.set_channels(format.channels)?;
+                hwp.set_rate(format.sample_rate, ValueOr::Nearest)?;
+                // One period per block, as many periods as NoiseMaker has blocks
+                hwp.set_period_size_near(frames_per_block, ValueOr::Nearest)?;
+                hwp.set_buffer_size_near(frames_per_block * format.blocks as alsa::pcm::Frames)?;
+                pcm.hw_params(&hwp)?;
+
+                eprintln!("ALSA period size = {} | buffer size = {}", hwp.get_period_size()?, hwp.get_buffer_size()?);
+            }
+            pcm.prepare()?;
+            return Ok(pcm);
+        }
+
+        fn alsa_format(sample_format: SampleFormat) -> Format {
+            return match sample_format {
+                SampleFormat::I16 => Format::s16(),
+                SampleFormat::I24 => Format::s24_3(),
+                SampleFormat::I32 => Format::s32(),
+                SampleFormat::F32 => Format::float(),
+            };
+        }
+
+        // Writes all of `samples`, recovering from underruns (or suspend) on the way.
+        // False once the device cannot be written any more.
+        fn write_block(pcm: &PCM, samples: &[u8]) -> bool {
+            // Raw bytes, the hw params already tell ALSA how they are encoded
+            let io = pcm.io_bytes();
+            let mut offset = 0;
+            while offset < samples.len() {
+                // writei blocks until the device has room, but may still take only part of the block
+                match io.writei(&samples[offset ..]) {
+                    Ok(frames) => offset += pcm.frames_to_bytes(frames as alsa::pcm::Frames) as usize,
+                    Err(e) => {
+                        if let Err(e) = pcm.try_recover(e, true) {
+                            eprintln!("ALSA write failed: {}", e);
+                            return false;
+                        }
+                    }
+                }
+            }
+            return true;
+        }
+
+        // Asks the device which rates, channel counts and formats it accepts.
+        // Busy or broken devices are listed unprobed, they cannot be validated (or opened) now.
+        fn probe(id: usize, name: &str) -> DeviceInfo {
+            let mut info = DeviceInfo {
+                id: id,
+                name: name.to_string(),
+                backend: "alsa",
+                sample_rates: Vec::new(),
+                channels: Vec::new(),
+                sample_formats: Vec::new(),
+                is_default: name == "default",
+                probed: false,
+            };
+
+            // Non blocking so a device held by someone else fails instead of hanging
+            let pcm = match PCM::new(name, Direction::Playback, true) {
+                Ok(pcm) => pcm,
+                Err(_) => return info,
+            };
+            if let Ok(hwp) = HwParams::any(&pcm) {
+                info.probed = true;
+                info.sample_rates = STANDARD_SAMPLE_RATES.iter().cloned().filter(|rate| hwp.test_rate(*rate).is_ok()).collect();
+                info.channels = (1..=8).filter(|channels| hwp.test_channels(*channels).is_ok()).collect();
+                let formats = [SampleFormat::I16, SampleFormat::I24, SampleFormat::I32, SampleFormat::F32];
+                info.sample_formats = formats.iter().cloned().filter(|format| hwp.test_format(Self::alsa_format(*format)).is_ok()).collect();
+            }
+            return info;
+        }
+
+        fn device_names(&self) -> Vec<String> {
+            let mut devices: Vec<String> = vec!["default".to_string()];
+
+            // Every playback device of every card, raw and through the plug layer
+            for card in alsa::card::Iter::new().filter_map(|card| card.ok()) {
+                let ctl = match alsa::Ctl::from_card(&card, false) {
+                    Ok(ctl) => ctl,
+                    Err(_) => continue,
+                };
+                for device in alsa::ctl::DeviceIter::new(&ctl) {
+                    if ctl.pcm_info(device as u32, 0, Direction::Playback).is_err() {
+                        continue;
+                    }
+                    devices.push(format!("hw:{},{}", card.get_index(), device));
+                    devices.push(format!("plughw:{},{}", card.get_index(), device));
+                }
+            }
+
+            // Named PCMs from the alsa configuration (pulse, pipewire, dmix, ...)
+            if let Ok(hints) = alsa::device_name::HintIter::new_str(None, "pcm") {
+                for hint in hints {
+                    if hint.direction == Some(Direction::Capture) {
+                        continue;
+                    }
+                    if let Some(name) = hint.name {
+                        if !devices.contains(&name) {
+                            devices.push(name);
+                        }
+                    }
+                }
+            }
+
+            return devices;
+        }
+    }
+
+    impl AudioBackend for AlsaBackend {
+        fn enumerate(&self) -> Vec<DeviceInfo> {
+            return self.device_names().iter().enumerate().map(|(id, name)| Self::probe(id, name)).collect();
+        }
+
+        fn open(&mut self, device_id: usize, format: StreamFormat, signal: Arc<BlockSignal>) -> Result<(), NoiseError> {
+            let devices = self.device_names();
+            let name = match devices.get(device_id) {
+                Some(name) => name.clone(),
+                None => return Err(NoiseError::DeviceNotFound(format!("#{}", device_id))),
+            };
+
+            eprintln!("Opening ALSA device {}", name);
+            match Self::open_pcm(&name, &format) {
+                Ok(pcm) => {
+                    self.pcm = Some(pcm);
+                    self.signal = Some(signal);
+                    return Ok(());
+                }
+                Err(e) => {
+                    eprintln!("Failed to open ALSA device {}: {}", name, e);
+                    return Err(NoiseError::OpenFailed(e.errno() as i32));
+                }
+            }
+        }
+
+        fn submit(&mut self, _: usize, samples: &[u8]) {
+            if let Some(pcm) = self.pcm.as_ref() {
+                if !Self::write_block(pcm, samples) {
+                    self.pcm = None;
+                }
+            }
+
+            // Without a device the block loop would spin rendering into nothing, end it instead
+            if let Some(signal) = self.signal.as_ref() {
+                if self.pcm.is_some() {
+                    signal.block_done();
+                } else {
+                    signal.fail();
+                }
+            }
+        }
+
+        fn close(&mut self) {
+            if let Some(pcm) = self.pcm.take() {
+                let _ = pcm.drain();
+            }
+            self.signal = None;
+        }
+
+        // Devices without pause support simply underrun, submit() recovers on resume
+        fn pause(&mut self) {
+            if let Some(pcm) = self.pcm.as_ref() {
+                if pcm.hw_params_current().map(|hwp| hwp.can_pause()).unwrap_or(false) {
+                    let _ = pcm.pause(true);
+                }
+            }
+        }
+
+        fn resume(&mut self) {
+            if let Some(pcm) = self.pcm.as_ref() {
+                if pcm.state() == alsa::pcm::State::Paused {
+                    let _ = pcm.pause(false);
+                }
+            }
+        }
+    }
+
+    impl Drop for AlsaBackend {
+        fn drop(&mut self) { self.close(); }
+    }
+}
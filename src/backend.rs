@@ -0,0 +1,279 @@
+pub mod backend {
+
+    use crate::error::error::NoiseError;
+
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::time::Duration;
+
+    // Stream layout the backend is asked to open with
+    #[derive(Clone, Copy, Debug)]
+    pub struct StreamFormat {
+        pub sample_rate: u32,
+        pub channels: u32,
+        pub blocks: u32,
+        pub block_samples: u32,
+        pub sample_format: SampleFormat,
+    }
+
+    // Interleaved sample encodings a backend can be asked to write
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum SampleFormat {
+        I16, // s16le
+        I24, // s24le, packed into 3 bytes
+        I32, // s32le
+        F32, // f32le, IEEE float
+    }
+
+    impl SampleFormat {
+        pub fn name(&self) -> &'static str {
+            return match self {
+                SampleFormat::I16 => "s16le",
+                SampleFormat::I24 => "s24le",
+                SampleFormat::I32 => "s32le",
+                SampleFormat::F32 => "f32le",
+            };
+        }
+
+        pub fn from_name(name: &str) -> Option<SampleFormat> {
+            return match name {
+                "s16le" => Some(SampleFormat::I16),
+                "s24le" => Some(SampleFormat::I24),
+                "s32le" => Some(SampleFormat::I32),
+                "f32le" => Some(SampleFormat::F32),
+                _ => None,
+            };
+        }
+
+        pub fn bytes(&self) -> usize {
+            return match self {
+                SampleFormat::I16 => 2,
+                SampleFormat::I24 => 3,
+                SampleFormat::I32 | SampleFormat::F32 => 4,
+            };
+        }
+
+        pub fn bits(&self) -> u16 {
+            return self.bytes() as u16 * 8;
+        }
+
+        pub fn is_float(&self) -> bool {
+            return *self == SampleFormat::F32;
+        }
+    }
+
+    // Rates probed by backends that can only answer "is this rate supported"
+    pub const STANDARD_SAMPLE_RATES: [u32; 11] = [8000, 11025, 16000, 22050, 32000, 44100, 48000, 88200, 96000, 176400, 192000];
+
+    // What an output device is and what it accepts. Empty `sample_rates` / `channels`
+    // mean the device takes any value (files, pipes, sound servers that convert), unless
+    // `probed` is false: then the device could not be asked (busy, broken) and nothing is known.
+    #[derive(Clone, Debug)]
+    pub struct DeviceInfo {
+        pub id: usize, // Index passed to AudioBackend::open
+        pub name: String,
+        pub backend: &'static str,
+        pub sample_rates: Vec<u32>,
+        pub channels: Vec<u32>,
+        pub sample_formats: Vec<SampleFormat>,
+        pub is_default: bool,
+        pub probed: bool,
+    }
+
+    impl DeviceInfo {
+        // A device that takes whatever it is given
+        pub fn any(id: usize, name: &str, backend: &'static str, sample_formats: Vec<SampleFormat>) -> Self {
+            return Self {
+                id: id,
+                name: name.to_string(),
+                backend: backend,
+                sample_rates: Vec::new(),
+                channels: Vec::new(),
+                sample_formats: sample_formats,
+                is_default: id == 0,
+                probed: true,
+            };
+        }
+
+        pub fn supports_sample_rate(&self, sample_rate: u32) -> bool {
+            return self.sample_rates.is_empty() || self.sample_rates.contains(&sample_rate);
+        }
+
+        pub fn supports_channels(&self, channels: u32) -> bool {
+            return self.channels.is_empty() || self.channels.contains(&channels);
+        }
+
+        pub fn supports_sample_format(&self, sample_format: SampleFormat) -> bool {
+            return self.sample_formats.is_empty() || self.sample_formats.contains(&sample_format);
+        }
+    }
+
+    // Shared "block done" bookkeeping between NoiseMaker::main_thread and a backend.
+    // main_thread takes a free block before filling it, backends give it back
+    // once the device has finished with it (WOM_DONE for winmm).
+    pub struct BlockSignal {
+        block_free: AtomicU32,
+        interrupted: AtomicBool,
+        failed: AtomicBool, // The device takes no more blocks until it is opened again
+        mux_block_not_zero: Mutex<()>,
+        condition_variable: Condvar,
+    }
+
+    impl BlockSignal {
+        pub fn new(blocks: u32) -> Self {
+            return Self {
+                block_free: AtomicU32::new(blocks),
+                interrupted: AtomicBool::new(false),
+                failed: AtomicBool::new(false),
+                mux_block_not_zero: Mutex::new(()),
+                condition_variable: Condvar::new(),
+            };
+        }
+
+        pub fn block_free(&self) -> u32 {
+            return self.block_free.load(Ordering::SeqCst);
+        }
+
+        // Called by the backend when the device has consumed a block
+        pub fn block_done(&self) {
+            let _guard = self.mux_block_not_zero.lock().unwrap();
+            self.block_free.fetch_add(1, Ordering::SeqCst);
+            self.condition_variable.notify_one();
+        }
+
+        // Called by the backend instead of block_done() when the device cannot take any more
+        // blocks (file full, pipe closed). Every acquire() fails from then on.
+        pub fn fail(&self) {
+            let _guard = self.mux_block_not_zero.lock().unwrap();
+            self.failed.store(true, Ordering::SeqCst);
+            self.condition_variable.notify_all();
+        }
+
+        pub fn has_failed(&self) -> bool {
+            return self.failed.load(Ordering::SeqCst);
+        }
+
+        // Blocks the caller until a block is free and takes it.
+        // Returns false without taking a block once interrupt() or fail() was called.
+        pub fn acquire(&self) -> bool {
+            let mut guard = self.mux_block_not_zero.lock().unwrap();
+            while self.block_free.load(Ordering::SeqCst) == 0 && !self.interrupted.load(Ordering::SeqCst) && !self.failed.load(Ordering::SeqCst) {
+                guard = self.condition_variable.wait(guard).unwrap();
+            }
+            if self.interrupted.load(Ordering::SeqCst) || self.failed.load(Ordering::SeqCst) {
+                return false;
+            }
+            self.block_free.fetch_sub(1, Ordering::SeqCst);
+            return true;
+        }
+
+        // Wakes up a waiting acquire(), used to stop main_thread while the device holds every block
+        pub fn interrupt(&self) {
+            let _guard = self.mux_block_not_zero.lock().unwrap();
+            self.interrupted.store(true, Ordering::SeqCst);
+            self.condition_variable.notify_all();
+        }
+
+        pub fn clear_interrupt(&self) {
+            self.interrupted.store(false, Ordering::SeqCst);
+        }
+    }
+
+    // Fills the given interleaved buffer with the next samples, as floats (-1.0 ..= 1.0)
+    // since callback driven servers mix in float anyway
+    pub type RenderCallback = Box<dyn FnMut(&mut [f32]) + Send>;
+
+    pub trait AudioBackend: Send {
+        // Output devices this backend can open, `id` is the position in the list
+        fn enumerate(&self) -> Vec<DeviceInfo>;
+
+        // Opens the device at `device_id` (DeviceInfo::id).
+        // The backend must call `signal.block_done()` for every submitted block once played.
+        fn open(&mut self, device_id: usize, format: StreamFormat, signal: Arc<BlockSignal>) -> Result<(), NoiseError>;
+
+        // Hands the filled block with index `block` (0..format.blocks) to the device.
+        // `samples` holds format.block_samples samples encoded as format.sample_format.
+        fn submit(&mut self, block: usize, samples: &[u8]);
+
+        // Stops the device, resets it and gives back every buffer. Must be safe to call when not open.
+        fn close(&mut self);
+
+        // Holds / continues playback of the blocks already submitted, for devices that can
+        fn pause(&mut self) {}
+
+        fn resume(&mut self) {}
+
+        // Output latency negotiated with the device, if the backend can tell
+        fn latency(&self) -> Option<Duration> {
+            return None;
+        }
+
+        // Backends with their own realtime thread (JACK) pull samples through a RenderCallback
+        // instead of being fed blocks by main_thread. They are opened with open_callback().
+        fn is_callback_driven(&self) -> bool {
+            return false;
+        }
+
+        fn open_callback(&mut self, _device_id: usize, _format: StreamFormat, _render: RenderCallback) -> Result<(), NoiseError> {
+            return Err(NoiseError::BackendError("backend is not callback driven".to_string()));
+        }
+    }
+
+    // Backends that can be picked by name, e.g. from the command line
+    pub fn backend_names() -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if cfg!(windows) {
+            names.push("winmm");
+        }
+        if cfg!(all(target_os = "linux", feature = "alsa")) {
+            names.push("alsa");
+        }
+        if cfg!(all(target_os = "linux", feature = "pulse")) {
+            names.push("pulse");
+        }
+        if cfg!(feature = "jack") {
+            names.push("jack");
+        }
+        names.push("wav");
+        names.push("pcm[:path]");
+        return names;
+    }
+
+    pub fn backend_by_name(name: &str) -> Option<Box<dyn AudioBackend>> {
+        // pcm:<path>, stdout unless given
+        if name == "pcm" || name.starts_with("pcm:") {
+            let target = name.split_once(':').map(|(_, path)| path).unwrap_or(crate::pcm_backend::pcm_backend::STDOUT_TARGET);
+            return Some(Box::new(crate::pcm_backend::pcm_backend::PcmBackend::new(target)));
+        }
+
+        return match name {
+            #[cfg(windows)]
+            "winmm" => Some(Box::new(crate::winmm_backend::winmm_backend::WinMmBackend::new())),
+            #[cfg(all(target_os = "linux", feature = "alsa"))]
+            "alsa" => Some(Box::new(crate::alsa_backend::alsa_backend::AlsaBackend::new())),
+            #[cfg(all(target_os = "linux", feature = "pulse"))]
+            "pulse" => Some(Box::new(crate::pulse_backend::pulse_backend::PulseBackend::new("synthesizer"))),
+            #[cfg(feature = "jack")]
+            "jack" => Some(Box::new(crate::jack_backend::jack_backend::JackBackend::new("synthesizer"))),
+            "wav" => Some(Box::new(crate::wav_backend::wav_backend::WavBackend::new("output.wav"))),
+            _ => None,
+        };
+    }
+
+    #[cfg(windows)]
+    pub fn default_backend() -> Result<Box<dyn AudioBackend>, NoiseError> {
+        return Ok(Box::new(crate::winmm_backend::winmm_backend::WinMmBackend::new()));
+    }
+
+    #[cfg(all(target_os = "linux", feature = "alsa"))]
+    pub fn default_backend() -> Result<Box<dyn AudioBackend>, NoiseError> {
+        return Ok(Box::new(crate::alsa_backend::alsa_backend::AlsaBackend::new()));
+    }
+
+    // No sound card support in this build. A file backend would not be paced by anything
+    // and grow without bound while the keyboard loop runs, so there is no fallback.
+    #[cfg(not(any(windows, all(target_os = "linux", feature = "alsa"))))]
+    pub fn default_backend() -> Result<Box<dyn AudioBackend>, NoiseError> {
+        return Err(NoiseError::BackendError("no sound card backend in this build, pick one with --backend or use --render".to_string()));
+    }
+}
@@ -0,0 +1,26 @@
+pub mod command {
+
+    // Synth parameters that can be changed while it is running
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Param {
+        Frequency, // Hz of the default patch
+        Volume,    // Linear gain applied to the output
+    }
+
+    // Control to audio thread messages
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Command {
+        NoteOn { frequency: f64 },
+        NoteOff,
+        SetParam { param: Param, value: f64 },
+        SetPan { voice: usize, pan: f64 }, // -1.0 (left) ..= 1.0 (right)
+    }
+
+    // A command and the engine time (seconds, see NoiseMaker::get_time) it takes effect at.
+    // Times that already passed apply at the start of the next block.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct TimedCommand {
+        pub time: f64,
+        pub command: Command,
+    }
+}
@@ -0,0 +1,92 @@
+pub mod convert {
+
+    use crate::backend::backend::SampleFormat;
+
+    // Last stage of the pipeline: float samples (nominally -1.0 ..= 1.0) to what the device takes.
+    // Plain loops over slices so the compiler can vectorize them. Every format clips to
+    // -1.0 ..= 1.0 (NaN becomes silence), integers are scaled by their positive maximum so 0.0 stays 0
+    // and rounded to the nearest step. See dither.rs for what to do about the rounding error.
+
+    // Encodes `input` little endian into `out`, which holds input.len() * format.bytes() bytes
+    pub fn encode(format: SampleFormat, input: &[f32], out: &mut [u8]) {
+        match format {
+            SampleFormat::I16 => f32_to_i16(input, out),
+            SampleFormat::I24 => f32_to_i24(input, out),
+            SampleFormat::I32 => f32_to_i32(input, out),
+            SampleFormat::F32 => f32_to_f32(input, out),
+        }
+    }
+
+    // Size of one integer step in float, 0.0 for float formats
+    pub fn lsb(format: SampleFormat) -> f32 {
+        return match format {
+            SampleFormat::I16 => 1.0 / i16::MAX as f32,
+            SampleFormat::I24 => 1.0 / 8388607.0,
+            SampleFormat::I32 => 1.0 / i32::MAX as f32,
+            SampleFormat::F32 => 0.0,
+        };
+    }
+
+    fn clamp(x: f32) -> f32 {
+        // clamp() lets NaN through, float output would hand it to the device as is
+        if x.is_nan() {
+            return 0.0;
+        }
+        return x.clamp(-1.0, 1.0);
+    }
+
+    pub fn f32_to_i16(input: &[f32], out: &mut [u8]) {
+        let scale = i16::MAX as f32;
+        for (bytes, x) in out.chunks_exact_mut(2).zip(input.iter()) {
+            bytes.copy_from_slice(&((clamp(*x) * scale).round() as i16).to_le_bytes());
+        }
+    }
+
+    // Packed, the low three bytes of an i32
+    pub fn f32_to_i24(input: &[f32], out: &mut [u8]) {
+        let scale = 8388607.0; // 2^23 - 1
+        for (bytes, x) in out.chunks_exact_mut(3).zip(input.iter()) {
+            bytes.copy_from_slice(&((clamp(*x) * scale).round() as i32).to_le_bytes()[..3]);
+        }
+    }
+
+    // Scaled in f64, i32::MAX is not representable in f32 and would wrap +1.0 around
+    pub fn f32_to_i32(input: &[f32], out: &mut [u8]) {
+        let scale = i32::MAX as f64;
+        for (bytes, x) in out.chunks_exact_mut(4).zip(input.iter()) {
+            bytes.copy_from_slice(&((clamp(*x) as f64 * scale).round() as i32).to_le_bytes());
+        }
+    }
+
+    pub fn f32_to_f32(input: &[f32], out: &mut [u8]) {
+        for (bytes, x) in out.chunks_exact_mut(4).zip(input.iter()) {
+            bytes.copy_from_slice(&clamp(*x).to_le_bytes());
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn encoded(format: SampleFormat, input: &[f32]) -> Vec<u8> {
+            let mut out = vec![0u8; input.len() * format.bytes()];
+            encode(format, input, &mut out);
+            return out;
+        }
+
+        #[test]
+        fn nan_is_silence() {
+            assert_eq!(encoded(SampleFormat::I16, &[f32::NAN]), vec![0, 0]);
+            assert_eq!(encoded(SampleFormat::I24, &[f32::NAN]), vec![0, 0, 0]);
+            assert_eq!(encoded(SampleFormat::I32, &[f32::NAN]), vec![0, 0, 0, 0]);
+            assert_eq!(encoded(SampleFormat::F32, &[f32::NAN]), 0.0f32.to_le_bytes().to_vec());
+        }
+
+        #[test]
+        fn full_scale_clips_without_wrapping() {
+            assert_eq!(encoded(SampleFormat::I16, &[2.0, -2.0]), [i16::MAX.to_le_bytes(), (-i16::MAX).to_le_bytes()].concat());
+            assert_eq!(encoded(SampleFormat::I24, &[1.0]), vec![0xFF, 0xFF, 0x7F]);
+            assert_eq!(encoded(SampleFormat::I32, &[1.0, -1.0]), [i32::MAX.to_le_bytes(), (-i32::MAX).to_le_bytes()].concat());
+        }
+    }
+}
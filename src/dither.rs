@@ -0,0 +1,172 @@
+pub mod dither {
+
+    use crate::backend::backend::SampleFormat;
+    use crate::convert::convert;
+
+    // Noise added to the float mix right before it is quantized to an integer format,
+    // so quiet signals (envelope tails) turn into low level hiss instead of distortion
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Dither {
+        None,
+        Rectangular, // +-0.5 LSB uniform, removes distortion but noise level follows the signal
+        Triangular,  // +-1 LSB TPDF, noise independent of the signal
+        NoiseShaped, // TPDF with first order error feedback, pushes the noise up in frequency
+    }
+
+    impl Dither {
+        pub fn name(&self) -> &'static str {
+            return match self {
+                Dither::None => "none",
+                Dither::Rectangular => "rect",
+                Dither::Triangular => "tpdf",
+                Dither::NoiseShaped => "shaped",
+            };
+        }
+
+        pub fn from_name(name: &str) -> Option<Dither> {
+            return match name {
+                "none" => Some(Dither::None),
+                "rect" => Some(Dither::Rectangular),
+                "tpdf" => Some(Dither::Triangular),
+                "shaped" => Some(Dither::NoiseShaped),
+                _ => None,
+            };
+        }
+    }
+
+    // Dither state for one stream, runs in place on interleaved blocks before convert::encode
+    pub struct Ditherer {
+        dither: Dither,
+        channels: usize,
+        seed: u32,      // xorshift32 state, never 0
+        error: Vec<f32>, // Last quantization error per channel, for NoiseShaped
+    }
+
+    impl Ditherer {
+        pub fn new(dither: Dither, channels: u32) -> Self {
+            return Self {
+                dither: dither,
+                channels: channels.max(1) as usize,
+                seed: 0x2545_F491,
+                error: vec![0.0; channels.max(1) as usize],
+            };
+        }
+
+        // Same noise sequence again, e.g. when playback restarts from time 0
+        pub fn reset(&mut self) {
+            self.seed = 0x2545_F491;
+            self.error.iter_mut().for_each(|error| *error = 0.0);
+        }
+
+        // Uniform in 0.0 .. 1.0
+        fn random(&mut self) -> f32 {
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 17;
+            self.seed ^= self.seed << 5;
+            return (self.seed >> 8) as f32 / (1 << 24) as f32;
+        }
+
+        // Only 16 bit output is dithered. Float needs none, and a 24 / 32 bit step is at or below
+        // what f32 resolves next to a loud sample, so the noise would mostly round away with the
+        // mix (their rounding error sits at -138 dB and below anyway).
+        pub fn process(&mut self, block: &mut [f32], format: SampleFormat) {
+            if self.dither == Dither::None || format != SampleFormat::I16 {
+                return;
+            }
+            let lsb = convert::lsb(format);
+
+            for (n, sample) in block.iter_mut().enumerate() {
+                match self.dither {
+                    Dither::None => {}
+                    Dither::Rectangular => *sample += (self.random() - 0.5) * lsb,
+                    Dither::Triangular => *sample += (self.random() - self.random()) * lsb,
+                    Dither::NoiseShaped => {
+                        let channel = n % self.channels;
+                        let wanted = *sample - self.error[channel];
+                        let output = wanted + (self.random() - self.random()) * lsb;
+                        // What convert::encode will make of it. Clipped samples would feed back
+                        // huge errors, so the error is kept within a couple of LSB.
+                        let quantized = (output.clamp(-1.0, 1.0) / lsb).round() * lsb;
+                        self.error[channel] = (quantized - wanted).clamp(-2.0 * lsb, 2.0 * lsb);
+                        *sample = output;
+                    }
+                }
+            }
+        }
+    }
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const LENGTH: usize = 1 << 18;
+
+        fn lsb() -> f32 {
+            return convert::lsb(SampleFormat::I16);
+        }
+
+        // Dither of `dither` on a block of silence, in LSB
+        fn noise(dither: Dither) -> Vec<f32> {
+            let mut block = vec![0.0; LENGTH];
+            Ditherer::new(dither, 1).process(&mut block, SampleFormat::I16);
+            return block.iter().map(|x| x / lsb()).collect();
+        }
+
+        fn mean(x: &[f32]) -> f64 {
+            return x.iter().map(|x| *x as f64).sum::<f64>() / x.len() as f64;
+        }
+
+        #[test]
+        fn dither_stays_within_its_range() {
+            assert!(noise(Dither::Rectangular).iter().all(|x| x.abs() <= 0.5));
+            assert!(noise(Dither::Triangular).iter().all(|x| x.abs() <= 1.0));
+        }
+
+        #[test]
+        fn dither_has_no_dc() {
+            for dither in [Dither::Rectangular, Dither::Triangular, Dither::NoiseShaped].iter() {
+                assert!(mean(&noise(*dither)).abs() < 0.01, "{:?}", dither);
+            }
+        }
+
+        #[test]
+        fn triangular_has_a_triangular_spread() {
+            let noise = noise(Dither::Triangular);
+            // Variance of the difference of two uniforms, 1/6, and 3/4 of it within +-0.5
+            let variance = noise.iter().map(|x| (*x as f64).powi(2)).sum::<f64>() / LENGTH as f64;
+            assert!((variance - 1.0 / 6.0).abs() < 0.005, "variance {}", variance);
+            let inner = noise.iter().filter(|x| x.abs() < 0.5).count() as f64 / LENGTH as f64;
+            assert!((inner - 0.75).abs() < 0.01, "{} within +-0.5 LSB", inner);
+        }
+
+        #[test]
+        fn noise_shaped_error_stays_within_two_lsb() {
+            // A quiet sine, then one driven far into clipping
+            let input: Vec<f32> = (0 .. 8192).map(|n| {
+                let amplitude = if n < 4096 { 0.001 } else { 1.5 };
+                amplitude * (n as f32 * 0.05).sin()
+            }).collect();
+
+            let mut ditherer = Ditherer::new(Dither::NoiseShaped, 1);
+            for (n, x) in input.iter().enumerate() {
+                let mut sample = [*x];
+                ditherer.process(&mut sample, SampleFormat::I16);
+                assert!(ditherer.error[0].abs() <= 2.0 * lsb() * 1.0001, "error {} LSB at {}", ditherer.error[0] / lsb(), n);
+
+                // Unclipped, the feedback moves the rounding by at most 1.5 LSB either way
+                if n < 4096 {
+                    let quantized = (sample[0] / lsb()).round() * lsb();
+                    assert!((quantized - x).abs() <= 3.0 * lsb() * 1.0001);
+                }
+            }
+        }
+
+        #[test]
+        fn only_16_bit_output_is_dithered() {
+            for format in [SampleFormat::I24, SampleFormat::I32, SampleFormat::F32].iter() {
+                let mut block = vec![0.25; 64];
+                Ditherer::new(Dither::Triangular, 1).process(&mut block, *format);
+                assert!(block.iter().all(|x| *x == 0.25));
+            }
+        }
+    }
+}
@@ -16,7 +16,7 @@ pub mod envelope {
 
     impl EnvelopeADSR {
         pub fn new() -> Self {
-            println!("Initialized envelope");
+            eprintln!("Initialized envelope");
             return Self {
                 attack_time: 0.01,
                 decay_time: 0.01,
@@ -0,0 +1,36 @@
+pub mod error {
+
+    use std::fmt;
+
+    // Everything that can go wrong while opening, running or closing a NoiseMaker
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum NoiseError {
+        DeviceNotFound(String),     // No output device with that name
+        OpenFailed(i32),            // Backend error code (MMRESULT, errno, ...)
+        FormatUnsupported(String),  // Device does not take the requested rate / channels / format
+        AlreadyRunning,             // create() called on a running NoiseMaker
+        QueueFull,                  // Audio thread is not keeping up with the commands sent to it
+        BackendError(String),       // Anything the backend only has a message for
+        LoadFailed(String),         // Reading or decoding a file (wavetables, samples) failed
+        LimitReached(String),       // More voices / effects than the synth keeps room for
+    }
+
+    impl fmt::Display for NoiseError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            return match self {
+                NoiseError::DeviceNotFound(name) => write!(f, "output device {} not found", name),
+                NoiseError::OpenFailed(code) => write!(f, "failed to open output device (error {})", code),
+                NoiseError::FormatUnsupported(reason) => write!(f, "unsupported format: {}", reason),
+                NoiseError::AlreadyRunning => write!(f, "already running"),
+                NoiseError::QueueFull => write!(f, "command queue is full"),
+                NoiseError::BackendError(message) => write!(f, "backend error: {}", message),
+                NoiseError::LoadFailed(reason) => write!(f, "failed to load {}", reason),
+                NoiseError::LimitReached(what) => write!(f, "too many {}", what),
+            };
+        }
+    }
+
+    impl std::error::Error for NoiseError {}
+
+    pub type NoiseResult<T> = Result<T, NoiseError>;
+}
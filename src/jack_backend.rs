@@ -0,0 +1,166 @@
+pub mod jack_backend {
+
+    extern crate jack;
+
+    use crate::backend::backend::{AudioBackend, BlockSignal, DeviceInfo, RenderCallback, SampleFormat, StreamFormat};
+    use crate::error::error::NoiseError;
+
+    use jack::{AudioOut, Client, ClientOptions, Control, Port, PortFlags, ProcessScope};
+
+    use std::sync::Arc;
+
+    const AUDIO_PORT_TYPE: &str = "32 bit float mono audio";
+
+    // Realtime side, owned by the JACK process thread
+    pub struct JackProcess {
+        ports: Vec<Port<AudioOut>>,
+        render: RenderCallback,
+        scratch: Vec<f32>,
+    }
+
+    impl jack::ProcessHandler for JackProcess {
+        fn process(&mut self, _: &Client, ps: &ProcessScope) -> Control {
+            let channels = self.ports.len();
+            let frames = ps.n_frames() as usize;
+
+            // Only grows if the server buffer size went up since activation
+            if self.scratch.len() != frames * channels {
+                self.scratch.resize(frames * channels, 0.0);
+            }
+            (self.render)(&mut self.scratch);
+
+            // Deinterleave into one port per channel
+            for (channel, port) in self.ports.iter_mut().enumerate() {
+                let out = port.as_mut_slice(ps);
+                for (n, sample) in out.iter_mut().enumerate() {
+                    *sample = self.scratch[n * channels + channel];
+                }
+            }
+            return Control::Continue;
+        }
+    }
+
+    // JACK client with one output port per channel. Unlike the other backends the sound is
+    // generated from JACK's process callback, so NoiseMaker does not run main_thread for it.
+    // Devices are the JACK clients owning audio inputs (e.g. "system"), our ports get connected to them.
+    pub struct JackBackend {
+        client_name: String,
+        client: Option<jack::AsyncClient<(), JackProcess>>,
+    }
+
+    impl JackBackend {
+        pub fn new(client_name: &str) -> Self {
+            return Self {
+                client_name: client_name.to_string(),
+                client: None,
+            };
+        }
+
+        fn input_ports(client: &Client, owner: &str) -> Vec<String> {
+            let pattern = format!("^{}:", owner);
+            return client.ports(Some(&pattern), Some(AUDIO_PORT_TYPE), PortFlags::IS_INPUT);
+        }
+    }
+
+    impl AudioBackend for JackBackend {
+        fn enumerate(&self) -> Vec<DeviceInfo> {
+            let mut devices: Vec<DeviceInfo> = Vec::new();
+            let client = match Client::new(&format!("{}_probe", self.client_name), ClientOptions::NO_START_SERVER) {
+                Ok((client, _)) => client,
+                Err(_) => return devices,
+            };
+
+            // Physical outputs first (the default), then other applications
+            let physical = client.ports(None, Some(AUDIO_PORT_TYPE), PortFlags::IS_INPUT | PortFlags::IS_PHYSICAL);
+            let mut ports = physical.clone();
+            ports.extend(client.ports(None, Some(AUDIO_PORT_TYPE), PortFlags::IS_INPUT));
+            for port in ports.iter() {
+                if let Some(owner) = port.split(':').next() {
+                    if devices.iter().any(|x| x.name == owner) {
+                        continue;
+                    }
+                    let id = devices.len();
+                    devices.push(DeviceInfo {
+                        id: id,
+                        name: owner.to_string(),
+                        backend: "jack",
+                        // Everything runs at the server's rate, any number of ports can be registered
+                        sample_rates: vec![client.sample_rate() as u32],
+                        channels: Vec::new(),
+                        sample_formats: vec![SampleFormat::F32],
+                        is_default: id == 0 && !physical.is_empty(),
+                        probed: true,
+                    });
+                }
+            }
+            return devices;
+        }
+
+        fn open(&mut self, _: usize, _: StreamFormat, _: Arc<BlockSignal>) -> Result<(), NoiseError> {
+            return Err(NoiseError::BackendError("JACK pulls audio from its own callback, use open_callback".to_string()));
+        }
+
+        fn submit(&mut self, _: usize, _: &[u8]) {}
+
+        fn close(&mut self) {
+            if let Some(client) = self.client.take() {
+                if let Err(e) = client.deactivate() {
+                    eprintln!("Failed to deactivate JACK client: {}", e);
+                }
+            }
+        }
+
+        fn is_callback_driven(&self) -> bool {
+            return true;
+        }
+
+        fn open_callback(&mut self, device_id: usize, format: StreamFormat, render: RenderCallback) -> Result<(), NoiseError> {
+            let devices = self.enumerate();
+
+            let (client, _) = match Client::new(&self.client_name, ClientOptions::NO_START_SERVER) {
+                Ok(client) => client,
+                Err(e) => return Err(NoiseError::BackendError(format!("failed to connect to JACK: {}", e))),
+            };
+
+            if client.sample_rate() != format.sample_rate as usize {
+                return Err(NoiseError::FormatUnsupported(format!("JACK runs at {}Hz but {}Hz was requested", client.sample_rate(), format.sample_rate)));
+            }
+
+            let mut ports: Vec<Port<AudioOut>> = Vec::new();
+            for channel in 0..format.channels {
+                match client.register_port(&format!("out_{}", channel + 1), AudioOut::default()) {
+                    Ok(port) => ports.push(port),
+                    Err(e) => return Err(NoiseError::BackendError(format!("failed to register JACK port: {}", e))),
+                }
+            }
+            let port_names: Vec<String> = ports.iter().filter_map(|port| port.name().ok()).collect();
+
+            let scratch = vec![0.0; client.buffer_size() as usize * format.channels as usize];
+            let process = JackProcess { ports: ports, render: render, scratch: scratch };
+            let active = match client.activate_async((), process) {
+                Ok(active) => active,
+                Err(e) => return Err(NoiseError::BackendError(format!("failed to activate JACK client: {}", e))),
+            };
+
+            // Connect out_N to the chosen client's N-th input, wrapping around if it has fewer
+            if let Some(device) = devices.get(device_id) {
+                let inputs = Self::input_ports(active.as_client(), &device.name);
+                if !inputs.is_empty() {
+                    for (n, port_name) in port_names.iter().enumerate() {
+                        let input = &inputs[n % inputs.len()];
+                        if let Err(e) = active.as_client().connect_ports_by_name(port_name, input) {
+                            eprintln!("Failed to connect {} to {}: {}", port_name, input, e);
+                        }
+                    }
+                }
+            }
+
+            self.client = Some(active);
+            return Ok(());
+        }
+    }
+
+    impl Drop for JackBackend {
+        fn drop(&mut self) { self.close(); }
+    }
+}
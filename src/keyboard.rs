@@ -0,0 +1,49 @@
+pub mod keyboard {
+
+    // White and black keys, lowest note first
+    pub const KEY_COUNT: usize = 16;
+
+    #[cfg(windows)]
+    pub fn start() {}
+
+    #[cfg(windows)]
+    pub fn key_down(k: usize) -> bool {
+        extern crate winapi;
+        use winapi::um::winuser;
+
+        unsafe {
+            return winuser::GetAsyncKeyState(b"ZSXCFVGBNJMK\xbcL\xbe\xbf"[k] as i32) as u16 & 0x8000 != 0;
+        }
+    }
+
+    // Without a global key state to poll, notes are read from stdin instead:
+    // a line starting with one of the keys holds that note, an empty line releases it.
+    #[cfg(not(windows))]
+    static STDIN_KEY: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(-1);
+
+    #[cfg(not(windows))]
+    pub fn start() {
+        use std::io::BufRead;
+        use std::sync::atomic::Ordering;
+
+        eprintln!("Type one of \"zsxcfvgbnjmk,l./\" and press enter to play a note, an empty line to release it");
+        std::thread::spawn(|| {
+            let stdin = std::io::stdin();
+            for line in stdin.lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                let key = line.chars().next()
+                    .and_then(|c| "zsxcfvgbnjmk,l./".find(c.to_ascii_lowercase()))
+                    .map_or(-1, |k| k as i32);
+                STDIN_KEY.store(key, Ordering::SeqCst);
+            }
+        });
+    }
+
+    #[cfg(not(windows))]
+    pub fn key_down(k: usize) -> bool {
+        return STDIN_KEY.load(std::sync::atomic::Ordering::SeqCst) == k as i32;
+    }
+}
@@ -0,0 +1,431 @@
+pub mod loopback_backend {
+
+    use crate::backend::backend::{AudioBackend, BlockSignal, DeviceInfo, SampleFormat, StreamFormat};
+    use crate::error::error::NoiseError;
+
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::time::Duration;
+
+    pub const LOOPBACK_DEVICE: &str = "loopback";
+
+    struct LoopbackState {
+        format: Option<StreamFormat>,
+        signal: Option<Arc<BlockSignal>>,
+
+        capacity: usize,
+        history: VecDeque<Vec<u8>>,  // Last `capacity` submitted blocks as encoded, oldest first
+        history_format: SampleFormat, // What `history` is encoded as, kept after close
+        in_flight: VecDeque<usize>,  // Block indices "queued on the device", waiting for the clock
+
+        submitted: u64,
+        completed: u64,
+    }
+
+    struct LoopbackShared {
+        state: Mutex<LoopbackState>,
+        changed: Condvar,
+    }
+
+    // In-memory device. Submitted blocks are kept in a ring buffer and only released
+    // (like WOM_DONE in the winmm backend) when the virtual clock is advanced through a LoopbackHandle.
+    pub struct LoopbackBackend {
+        shared: Arc<LoopbackShared>,
+    }
+
+    // Inspection and clock side of a LoopbackBackend, usable after the backend was moved into NoiseMaker
+    #[derive(Clone)]
+    pub struct LoopbackHandle {
+        shared: Arc<LoopbackShared>,
+    }
+
+    impl LoopbackBackend {
+        // `capacity` is the number of most recent blocks kept for inspection
+        pub fn new(capacity: usize) -> Self {
+            return Self {
+                shared: Arc::new(LoopbackShared {
+                    state: Mutex::new(LoopbackState {
+                        format: None,
+                        signal: None,
+                        capacity: capacity,
+                        history: VecDeque::with_capacity(capacity),
+                        history_format: SampleFormat::I16,
+                        in_flight: VecDeque::new(),
+                        submitted: 0,
+                        completed: 0,
+                    }),
+                    changed: Condvar::new(),
+                }),
+            };
+        }
+
+        pub fn handle(&self) -> LoopbackHandle {
+            return LoopbackHandle { shared: Arc::clone(&self.shared) };
+        }
+    }
+
+    impl AudioBackend for LoopbackBackend {
+        fn enumerate(&self) -> Vec<DeviceInfo> {
+            return vec![DeviceInfo::any(0, LOOPBACK_DEVICE, "loopback", vec![SampleFormat::I16, SampleFormat::I24, SampleFormat::I32, SampleFormat::F32])];
+        }
+
+        fn open(&mut self, _: usize, format: StreamFormat, signal: Arc<BlockSignal>) -> Result<(), NoiseError> {
+            let mut state = self.shared.state.lock().unwrap();
+            state.format = Some(format);
+            state.signal = Some(signal);
+            state.history.clear();
+            state.history_format = format.sample_format;
+            state.in_flight.clear();
+            state.submitted = 0;
+            state.completed = 0;
+            self.shared.changed.notify_all();
+            return Ok(());
+        }
+
+        fn submit(&mut self, block: usize, samples: &[u8]) {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.capacity > 0 {
+                if state.history.len() == state.capacity {
+                    state.history.pop_front();
+                }
+                state.history.push_back(samples.to_vec());
+            }
+            state.in_flight.push_back(block);
+            state.submitted += 1;
+            self.shared.changed.notify_all();
+        }
+
+        fn close(&mut self) {
+            let mut state = self.shared.state.lock().unwrap();
+            state.format = None;
+            state.signal = None;
+            state.in_flight.clear();
+            self.shared.changed.notify_all();
+        }
+    }
+
+    impl LoopbackHandle {
+        // Advances the virtual clock by up to `blocks` blocks, signalling "block done" for each
+        // block that was in flight. Returns how many blocks were played.
+        pub fn tick(&self, blocks: usize) -> usize {
+            let mut state = self.shared.state.lock().unwrap();
+            let mut played = 0;
+            while played < blocks {
+                if state.in_flight.pop_front().is_none() {
+                    break;
+                }
+                state.completed += 1;
+                if let Some(signal) = state.signal.as_ref() {
+                    signal.block_done();
+                }
+                played += 1;
+            }
+            self.shared.changed.notify_all();
+            return played;
+        }
+
+        // Plays everything currently in flight
+        pub fn drain(&self) -> usize {
+            return self.tick(usize::MAX);
+        }
+
+        // Waits until at least `count` blocks were submitted since open, false on timeout
+        pub fn wait_submitted(&self, count: u64, timeout: Duration) -> bool {
+            let state = self.shared.state.lock().unwrap();
+            let (_state, result) = self.shared.changed
+                .wait_timeout_while(state, timeout, |state| state.submitted < count)
+                .unwrap();
+            return !result.timed_out();
+        }
+
+        pub fn is_open(&self) -> bool {
+            return self.shared.state.lock().unwrap().format.is_some();
+        }
+
+        pub fn format(&self) -> Option<StreamFormat> {
+            return self.shared.state.lock().unwrap().format;
+        }
+
+        pub fn submitted(&self) -> u64 {
+            return self.shared.state.lock().unwrap().submitted;
+        }
+
+        pub fn completed(&self) -> u64 {
+            return self.shared.state.lock().unwrap().completed;
+        }
+
+        // Block indices queued on the virtual device, oldest first
+        pub fn in_flight(&self) -> Vec<usize> {
+            return self.shared.state.lock().unwrap().in_flight.iter().cloned().collect();
+        }
+
+        // Free block count as seen by NoiseMaker, None while closed
+        pub fn block_free(&self) -> Option<u32> {
+            let state = self.shared.state.lock().unwrap();
+            return state.signal.as_ref().map(|signal| signal.block_free());
+        }
+
+        // Copy of the retained blocks as submitted, oldest first
+        pub fn blocks(&self) -> Vec<Vec<u8>> {
+            return self.shared.state.lock().unwrap().history.iter().cloned().collect();
+        }
+
+        // The retained blocks concatenated into one byte stream
+        pub fn bytes(&self) -> Vec<u8> {
+            let state = self.shared.state.lock().unwrap();
+            return state.history.iter().flat_map(|block| block.iter().cloned()).collect();
+        }
+
+        // The retained blocks decoded back to samples, scaled to i16 whatever the stream format
+        pub fn samples(&self) -> Vec<i16> {
+            let state = self.shared.state.lock().unwrap();
+            let sample_format = state.history_format;
+            let bytes: Vec<u8> = state.history.iter().flat_map(|block| block.iter().cloned()).collect();
+            return bytes.chunks_exact(sample_format.bytes()).map(|x| match sample_format {
+                SampleFormat::I16 => i16::from_le_bytes([x[0], x[1]]),
+                SampleFormat::I24 => i16::from_le_bytes([x[1], x[2]]),
+                SampleFormat::I32 => i16::from_le_bytes([x[2], x[3]]),
+                SampleFormat::F32 => (f32::from_le_bytes([x[0], x[1], x[2], x[3]]) * i16::MAX as f32) as i16,
+            }).collect();
+        }
+
+        pub fn clear(&self) {
+            self.shared.state.lock().unwrap().history.clear();
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::command::command::{Command, Param};
+        use crate::noise::noise::{NoiseArgs, NoiseMaker};
+        use crate::source::source::{ProcessContext, SoundSource};
+        use crate::additive::additive::AdditiveOscillator;
+        use crate::noise_generator::noise_generator::{NoiseColor, NoiseGenerator};
+        use std::thread::{self, ThreadId};
+
+        const TIMEOUT: Duration = Duration::from_secs(5);
+
+        // A NoiseMaker playing a constant 0.5 into a loopback device that keeps `capacity` blocks
+        fn open(capacity: usize) -> (NoiseMaker, LoopbackHandle) {
+            let backend = LoopbackBackend::new(capacity);
+            let handle = backend.handle();
+            let mut sound = NoiseMaker::with_backend(NoiseArgs::default(), Box::new(backend));
+            sound.set_user_function(Box::new(|ctx| ctx.output = 0.5)).unwrap();
+            sound.create(LOOPBACK_DEVICE.to_string()).unwrap();
+            return (sound, handle);
+        }
+
+        #[test]
+        fn main_thread_fills_every_block_then_waits() {
+            let (sound, handle) = open(0);
+            let blocks = NoiseArgs::default().blocks as u64;
+
+            assert!(handle.wait_submitted(blocks, TIMEOUT));
+            assert_eq!(handle.in_flight(), (0 .. blocks as usize).collect::<Vec<usize>>());
+            assert_eq!(handle.block_free(), Some(0));
+            assert_eq!(sound.get_frame(), blocks * NoiseArgs::default().block_samples as u64);
+
+            // Nothing is free, so main_thread has to sit on the condvar
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(handle.submitted(), blocks);
+        }
+
+        #[test]
+        fn tick_hands_blocks_back_in_order() {
+            let (_sound, handle) = open(0);
+            assert!(handle.wait_submitted(8, TIMEOUT));
+
+            assert_eq!(handle.tick(3), 3);
+            assert!(handle.wait_submitted(11, TIMEOUT));
+            assert_eq!(handle.in_flight(), vec![3, 4, 5, 6, 7, 0, 1, 2]);
+            assert_eq!(handle.completed(), 3);
+            assert_eq!(handle.block_free(), Some(0));
+
+            assert_eq!(handle.drain(), 8);
+            assert!(handle.wait_submitted(19, TIMEOUT));
+            assert_eq!(handle.in_flight(), vec![3, 4, 5, 6, 7, 0, 1, 2]);
+        }
+
+        #[test]
+        fn blocks_hold_the_rendered_samples() {
+            let (_sound, handle) = open(4);
+            assert!(handle.wait_submitted(8, TIMEOUT));
+
+            let blocks = handle.blocks();
+            assert_eq!(blocks.len(), 4);
+            assert!(blocks.iter().all(|block| block.len() == 512 * 2));
+            assert_eq!(handle.bytes(), blocks.concat());
+            // 0.5 * 32767 rounded
+            assert!(handle.samples().iter().all(|sample| *sample == 16384));
+
+            handle.clear();
+            assert!(handle.blocks().is_empty());
+        }
+
+        #[test]
+        fn resume_carries_on_with_the_next_block() {
+            let (mut sound, handle) = open(0);
+            assert!(handle.wait_submitted(8, TIMEOUT));
+            handle.tick(3);
+            assert!(handle.wait_submitted(11, TIMEOUT));
+
+            // The device keeps playing what it holds while generation is paused
+            sound.pause().unwrap();
+            assert_eq!(handle.tick(2), 2);
+            assert_eq!(handle.block_free(), Some(2));
+
+            // Blocks 5 .. 2 are still queued, so the next ones to fill are 3 and 4
+            sound.resume().unwrap();
+            assert!(handle.wait_submitted(13, TIMEOUT));
+            assert_eq!(handle.in_flight(), vec![5, 6, 7, 0, 1, 2, 3, 4]);
+            assert_eq!(handle.block_free(), Some(0));
+        }
+
+        #[test]
+        fn commands_sent_before_start_apply_from_the_first_sample() {
+            let backend = LoopbackBackend::new(8);
+            let handle = backend.handle();
+            let mut sound = NoiseMaker::with_backend(NoiseArgs::default(), Box::new(backend));
+            sound.set_user_function(Box::new(|ctx| ctx.output = if ctx.frequency == 440.0 { 0.5 } else { 0.0 })).unwrap();
+            sound.set_frequency_output(440.0);
+            sound.create(LOOPBACK_DEVICE.to_string()).unwrap();
+
+            assert!(handle.wait_submitted(8, TIMEOUT));
+            assert!(handle.samples().iter().all(|sample| *sample == 16384));
+        }
+
+        #[test]
+        fn send_at_applies_at_the_exact_frame() {
+            let backend = LoopbackBackend::new(8);
+            let handle = backend.handle();
+            let mut sound = NoiseMaker::with_backend(NoiseArgs::default(), Box::new(backend));
+            sound.set_user_function(Box::new(|ctx| ctx.output = ctx.frequency / 1000.0)).unwrap();
+
+            // Frame 300 of the first block, then two changes at once in the second one
+            let frame_time = |frame: f64| frame / NoiseArgs::default().sample_rate as f64;
+            sound.send_at(frame_time(300.0), Command::SetParam { param: Param::Frequency, value: 100.0 }).unwrap();
+            sound.send_at(frame_time(700.0), Command::SetParam { param: Param::Frequency, value: 200.0 }).unwrap();
+            sound.send_at(frame_time(700.0), Command::SetParam { param: Param::Frequency, value: 300.0 }).unwrap();
+            sound.create(LOOPBACK_DEVICE.to_string()).unwrap();
+            assert!(handle.wait_submitted(8, TIMEOUT));
+
+            // 0.1 and 0.3 of 32767, rounded
+            let samples = handle.samples();
+            assert!(samples[.. 300].iter().all(|sample| *sample == 0));
+            assert!(samples[300 .. 700].iter().all(|sample| *sample == 3277));
+            assert!(samples[700 ..].iter().all(|sample| *sample == 9830));
+        }
+
+        #[test]
+        fn dropped_frequency_is_not_reported() {
+            // Never started, so nothing drains the command queue
+            let sound = NoiseMaker::with_backend(NoiseArgs::default(), Box::new(LoopbackBackend::new(0)));
+            let mut frequency = 1.0;
+            while sound.send(Command::SetParam { param: Param::Frequency, value: frequency }).is_ok() {
+                frequency += 1.0;
+            }
+            assert_eq!(sound.get_frequency_output(), frequency - 1.0);
+        }
+
+        #[test]
+        fn blocks_must_hold_whole_frames() {
+            let args = NoiseArgs { channels: 3, block_samples: 512, ..NoiseArgs::default() };
+            let mut sound = NoiseMaker::with_backend(args, Box::new(LoopbackBackend::new(0)));
+            match sound.create(LOOPBACK_DEVICE.to_string()) {
+                Err(NoiseError::FormatUnsupported(_)) => {}
+                other => panic!("expected FormatUnsupported, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn samples_decode_after_stop() {
+            let backend = LoopbackBackend::new(2);
+            let handle = backend.handle();
+            let args = NoiseArgs { sample_format: SampleFormat::F32, ..NoiseArgs::default() };
+            let mut sound = NoiseMaker::with_backend(args, Box::new(backend));
+            sound.set_user_function(Box::new(|ctx| ctx.output = 0.5)).unwrap();
+            sound.create(LOOPBACK_DEVICE.to_string()).unwrap();
+            assert!(handle.wait_submitted(8, TIMEOUT));
+            assert_eq!(handle.format().map(|format| format.sample_format), Some(SampleFormat::F32));
+
+            sound.stop().unwrap();
+            assert!(handle.format().is_none());
+            assert_eq!(handle.samples().len(), 2 * 512);
+            assert!(handle.samples().iter().all(|sample| *sample == 16383));
+        }
+
+        #[test]
+        fn stop_gives_every_block_back() {
+            let (mut sound, handle) = open(0);
+            assert!(handle.wait_submitted(8, TIMEOUT));
+
+            sound.stop().unwrap();
+            assert!(!handle.is_open());
+            assert_eq!(handle.block_free(), None);
+            assert!(handle.in_flight().is_empty());
+            assert!(!sound.handle().is_running());
+
+            // Reopened with every block free and the clock back at 0
+            sound.start().unwrap();
+            assert!(handle.wait_submitted(8, TIMEOUT));
+            assert_eq!(handle.in_flight(), (0 .. 8).collect::<Vec<usize>>());
+            assert_eq!(sound.get_frame(), 8 * 512);
+        }
+
+        #[test]
+        fn restart_renders_the_same_samples() {
+            let backend = LoopbackBackend::new(8);
+            let handle = backend.handle();
+            let mut sound = NoiseMaker::with_backend(NoiseArgs::default(), Box::new(backend));
+            sound.set_source(Box::new(NoiseGenerator::new(NoiseColor::Pink, 0.5, 3))).unwrap();
+            sound.add_voice(Box::new(AdditiveOscillator::saw(16, 0.0, 0.25)), 0.0).unwrap();
+            sound.set_frequency_output(330.0);
+            sound.create(LOOPBACK_DEVICE.to_string()).unwrap();
+            assert!(handle.wait_submitted(8, TIMEOUT));
+            let first = handle.blocks();
+
+            sound.stop().unwrap();
+            handle.clear();
+            sound.start().unwrap();
+            assert!(handle.wait_submitted(8, TIMEOUT));
+            assert!(handle.blocks() == first);
+        }
+
+        // Constant 0.5 that remembers which thread dropped it
+        struct Tracked(Arc<Mutex<Option<ThreadId>>>);
+
+        impl SoundSource for Tracked {
+            fn process(&mut self, out: &mut [f32], _: &ProcessContext) {
+                out.iter_mut().for_each(|sample| *sample = 0.5);
+            }
+        }
+
+        impl Drop for Tracked {
+            fn drop(&mut self) {
+                *self.0.lock().unwrap() = Some(thread::current().id());
+            }
+        }
+
+        #[test]
+        fn replaced_sources_are_dropped_on_the_control_side() {
+            let backend = LoopbackBackend::new(0);
+            let handle = backend.handle();
+            let mut sound = NoiseMaker::with_backend(NoiseArgs::default(), Box::new(backend));
+            let dropped = Arc::new(Mutex::new(None));
+            sound.set_source(Box::new(Tracked(Arc::clone(&dropped)))).unwrap();
+            sound.create(LOOPBACK_DEVICE.to_string()).unwrap();
+            assert!(handle.wait_submitted(8, TIMEOUT));
+
+            // Swapped out by the audio thread at its next block, which only hands it back
+            sound.set_user_function(Box::new(|ctx| ctx.output = 0.0)).unwrap();
+            handle.tick(1);
+            assert!(handle.wait_submitted(9, TIMEOUT));
+            assert_eq!(*dropped.lock().unwrap(), None);
+
+            // The next change collects it
+            sound.clear_effects().unwrap();
+            assert_eq!(*dropped.lock().unwrap(), Some(thread::current().id()));
+        }
+    }
+}
@@ -1,126 +1,268 @@
-extern crate winapi;
 extern crate backtrace;
 
 mod noise;
 mod envelope;
+mod keyboard;
+mod backend;
+mod error;
+mod spsc;
+mod command;
+mod source;
+mod convert;
+mod dither;
+mod voice;
+mod oscillator;
+mod additive;
+mod wavetable;
+mod noise_generator;
+#[cfg(windows)]
+mod winmm_backend;
+#[cfg(all(target_os = "linux", feature = "alsa"))]
+mod alsa_backend;
+#[cfg(all(target_os = "linux", feature = "pulse"))]
+mod pulse_backend;
+#[cfg(feature = "jack")]
+mod jack_backend;
+mod wav_backend;
+#[cfg(test)]
+mod loopback_backend;
+mod pcm_backend;
 
 use noise::noise::{NoiseMaker, NoiseArgs};
+use wav_backend::wav_backend::WavBackend;
+use backend::backend::{DeviceInfo, SampleFormat};
+use dither::dither::Dither;
+use error::error::NoiseError;
+use source::source::SoundSource;
+use additive::additive::AdditiveOscillator;
+use wavetable::wavetable::{Wavetable, WavetableOscillator};
+use noise_generator::noise_generator::{NoiseColor, NoiseGenerator};
+use oscillator::oscillator::{Oscillator, Waveform};
 use envelope::envelope::EnvelopeADSR;
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicPtr, Ordering};
 use std::sync::{Mutex, Arc, Condvar, Weak};
-use std::f64::consts::PI;
 use std::mem;
 use std::thread;
 use std::ptr;
-use winapi::um::winuser;
+use std::time::Duration;
 use num::pow;
 use backtrace::Backtrace;
 //use crate::noise::noise::NoiseMaker;
 
 
-fn hz_to_angular(hertz: f64) -> f64 {
-    return hertz * 2.0 * PI;
-}
+//static mut FREQUENCY_OUTPUT: f64 = 0.0;
+const OCTAVE_BASE_FREQUENCY: f64 = 110.0; // A2
+//TWELVE_ROOT_OF_TWO = num::pow(2.0, 1.0 / 12.0);
+const TWELVE_ROOT_OF_TWO: f64 = 1.0594630943592952645618252949463;
 
-fn oscillate(hertz: f64, current_time: f64, osc_type: i32) -> f64 {
-    return match osc_type {
-        0 => f64::sin(hz_to_angular(hertz) * current_time),
-        1 => if f64::sin(hz_to_angular(hertz) * current_time) > 0.0 { 1.0 } else { -1.0 },
-        2 => f64::asin(f64::sin(hz_to_angular(hertz) * current_time)) * (2.0 / PI),
-        3 => {
-            let mut output = 0.0;
-            for n in 1 .. 100 {
-                output += (f64::sin(n as f64 * hz_to_angular(hertz) * current_time)) / n as f64;
-            }
-            return output * (2.0 / PI);
-        },
-        4 => (2.0 / PI) * (hertz * PI * (current_time % (1.0 / hertz)) - (PI / 2.0)),
-        _ => 0.0
+// synthesizer ... --source <name>, what the keyboard (or --render) plays.
+// Also wavetable:<file.wav>, the whole file played as one cycle of a wavetable.
+const SOURCE_NAMES: [&str; 7] = ["default", "additive", "wavetable", "pulse", "white", "pink", "brown"];
+
+// None for "default", the patch NoiseMaker plays until a source is set
+fn source_by_name(name: &str) -> Result<Option<Box<dyn SoundSource>>, NoiseError> {
+    if let Some(path) = name.strip_prefix("wavetable:") {
+        let table = Wavetable::load_wav(path, None)?;
+        return Ok(Some(Box::new(WavetableOscillator::new(Arc::new(table), 0.0, 0.5))));
     }
+    return Ok(match name {
+        "additive" => Some(Box::new(AdditiveOscillator::saw(64, 0.0, 0.5))),
+        "wavetable" => {
+            // Halfway between the triangle and the saw frame
+            let mut oscillator = WavetableOscillator::new(Arc::new(Wavetable::basic_shapes()), 0.0, 0.5);
+            oscillator.position = 0.5;
+            Some(Box::new(oscillator))
+        }
+        "pulse" => {
+            // Quarter duty, the hollow, nasal one
+            let mut oscillator = Oscillator::new(Waveform::PulseBandLimited, 0.0, 0.5);
+            oscillator.duty = 0.25;
+            Some(Box::new(oscillator))
+        }
+        "white" => Some(Box::new(NoiseGenerator::new(NoiseColor::White, 0.5, 0))),
+        "pink" => Some(Box::new(NoiseGenerator::new(NoiseColor::Pink, 1.0, 0))),
+        "brown" => Some(Box::new(NoiseGenerator::new(NoiseColor::Brown, 1.0, 0))),
+        _ => None,
+    });
 }
 
-fn make_noise(current_time: f64) -> f64 {
-    //let output: f64 = 
-    //println!("Making noise!!");
-    return 0.5 * f64::sin(110.0 * 2 as f64 * PI * current_time);
-    //return 0.1;
+// synthesizer --render <file.wav> [seconds]
+// Renders the chosen source at the octave base frequency without touching a sound card
+fn render_to_wav(path: &str, duration: f64, args: NoiseArgs, source: Option<Box<dyn SoundSource>>) {
+    let mut sound: NoiseMaker = NoiseMaker::with_backend(args, Box::new(WavBackend::new(path)));
+    if let Some(source) = source {
+        if let Err(e) = sound.set_source(source) {
+            eprintln!("Render failed: {}", e);
+            return;
+        }
+    }
+    sound.set_frequency_output(OCTAVE_BASE_FREQUENCY);
+    sound.set_envelop_note_on();
+    match sound.render(path.to_string(), duration) {
+        Ok(()) => eprintln!("Rendered {}s to {}", duration, path),
+        Err(e) => eprintln!("Render failed: {}", e),
+    }
 }
 
-//static mut FREQUENCY_OUTPUT: f64 = 0.0;
-static mut OCTAVE_BASE_FREQUENCY: f64 = 110.0; // A2
-static mut CURRENT_KEY: i32 = -1;
-static mut KEY_PRESSED: bool = false;
-static mut TWELVE_ROOT_OF_TWO: f64 = 0.0;
-
 fn main() {
     let bt = Backtrace::new();
-    println!("Hello, world!");
+    eprintln!("Hello, world!");
+
+    let args: Vec<String> = std::env::args().collect();
+
+    // synthesizer ... --format <s16le|s24le|s32le|f32le>
+    let sample_format = match args.iter().position(|x| x == "--format") {
+        Some(n) => match args.get(n + 1).and_then(|name| SampleFormat::from_name(name)) {
+            Some(sample_format) => sample_format,
+            None => {
+                eprintln!("Unknown sample format, available: s16le, s24le, s32le, f32le");
+                return;
+            }
+        },
+        None => SampleFormat::I16,
+    };
+    // synthesizer ... --dither <none|rect|tpdf|shaped>, for s16le
+    let dither = match args.iter().position(|x| x == "--dither") {
+        Some(n) => match args.get(n + 1).and_then(|name| Dither::from_name(name)) {
+            Some(dither) => dither,
+            None => {
+                eprintln!("Unknown dither, available: none, rect, tpdf, shaped");
+                return;
+            }
+        },
+        None => Dither::None,
+    };
+    let noise_args = NoiseArgs { sample_format: sample_format, dither: dither, ..NoiseArgs::default() };
+
+    let source_name = match args.iter().position(|x| x == "--source") {
+        Some(n) => match args.get(n + 1).filter(|name| SOURCE_NAMES.contains(&name.as_str()) || name.starts_with("wavetable:")) {
+            Some(name) => name.clone(),
+            None => {
+                eprintln!("Unknown source, available: {}, wavetable:<file.wav>", SOURCE_NAMES.join(", "));
+                return;
+            }
+        },
+        None => "default".to_string(),
+    };
+    let source = match source_by_name(&source_name) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Failed to set the source: {}", e);
+            return;
+        }
+    };
+
+    if args.len() > 2 && args[1] == "--render" {
+        let duration: f64 = args.get(3).and_then(|x| x.parse().ok()).unwrap_or(5.0);
+        render_to_wav(&args[2], duration, noise_args, source);
+        return;
+    }
+
+    // synthesizer --backend <name>
+    let backend = match args.iter().position(|x| x == "--backend") {
+        Some(n) => match args.get(n + 1).and_then(|name| backend::backend::backend_by_name(name)) {
+            Some(backend) => backend,
+            None => {
+                eprintln!("Unknown backend, available: {}", backend::backend::backend_names().join(", "));
+                return;
+            }
+        },
+        None => match backend::backend::default_backend() {
+            Ok(backend) => backend,
+            Err(e) => {
+                eprintln!("{}, available: {}", e, backend::backend::backend_names().join(", "));
+                return;
+            }
+        },
+    };
 
     //let sound = NoiseMaker::create();
     //NoiseMaker::new()
-    unsafe {
-        let devices: Vec<String> = NoiseMaker::enumerate();
-    
-        for d in devices.iter() {
-            println!("Found Output Device {}\n", d);
+    //let mut temporal = Box::new(100);
+    let mut sound: NoiseMaker = NoiseMaker::with_backend(noise_args, backend);
+    if let Some(source) = source {
+        if let Err(e) = sound.set_source(source) {
+            eprintln!("Failed to set the source: {}", e);
+            return;
+        }
+    }
+    let devices: Vec<DeviceInfo> = sound.enumerate();
+
+    for d in devices.iter() {
+        if !d.probed {
+            eprintln!("Found Output Device {} [{}]{} busy or unavailable\n", d.name, d.backend, if d.is_default { " (default)" } else { "" });
+            continue;
         }
+        eprintln!("Found Output Device {} [{}]{} rates {:?} channels {:?} formats {:?}\n",
+            d.name, d.backend, if d.is_default { " (default)" } else { "" }, d.sample_rates, d.channels, d.sample_formats);
+    }
+
+    let device = match devices.iter().find(|d| d.is_default).or(devices.first()) {
+        Some(device) => device.name.clone(),
+        None => {
+            eprintln!("No output devices found");
+            return;
+        }
+    };
+    if let Err(e) = sound.create(device) {
+        eprintln!("Failed to start: {}", e);
+        return;
+    }
+    if let Some(latency) = sound.get_latency() {
+        eprintln!("Output latency {}ms", latency.as_millis());
+    }
+
+    eprintln!("TWELVE_ROOT_OF_TWO = {}", TWELVE_ROOT_OF_TWO);
+    //FREQUENCY_OUTPUT = Box::new(0.0);
+
+    let mut current_key: i32 = -1;
+    //let mut frequency_output: f64 = Box::new()
+    let mut envelope: EnvelopeADSR = EnvelopeADSR::new();
+    //let mut key_pressed: bool = false;
     
-        //let mut temporal = Box::new(100);
-        let mut sound: NoiseMaker = NoiseMaker::new(NoiseArgs::default());
-        sound.create((*devices[0]).to_string());
-        sound.set_user_function(make_noise);
-
-        //TWELVE_ROOT_OF_TWO = num::pow(2.0, 1.0 / 12.0);
-        TWELVE_ROOT_OF_TWO = 1.0594630943592952645618252949463;
-        println!("TWELVE_ROOT_OF_TWO = {}", TWELVE_ROOT_OF_TWO);
-        //FREQUENCY_OUTPUT = Box::new(0.0);
-
-        // iflet mut current_key: i32 = -1;
-        //let mut frequency_output: f64 = Box::new()
-        let mut envelope: EnvelopeADSR = EnvelopeADSR::new();
-        //let mut key_pressed: bool = false;
-        
-        //let key_pressed = AtomicBool::new(false);
-
-
-        // let new_thread = thread::spawn(|| {
-        //     loop {
-
-        //     }
-        // });
-
-        // loop {
-        //     let a = 10;
-        //     println!("a = {}", a);
-        // }
-
-        
-
-        loop {
-            KEY_PRESSED = false;
-            for k in 0 .. 16 {
-                // White and black keys
-                if winuser::GetAsyncKeyState(b"ZSXCFVGBNJMK\xbcL\xbe\xbf"[k] as i32) as u16 & 0x8000 != 0 {
-                    if CURRENT_KEY != k as i32 {
-                        sound.set_frequency_output(OCTAVE_BASE_FREQUENCY * num::pow(TWELVE_ROOT_OF_TWO, k));
-                        sound.set_envelop_note_on();
-                        println!("\rNote On : {}s {}Hz", sound.get_time(), sound.get_frequency_output());
-                        CURRENT_KEY = k as i32;
-                    }
-                    KEY_PRESSED = true;
-                }
-            }
+    //let key_pressed = AtomicBool::new(false);
+
+
+    // let new_thread = thread::spawn(|| {
+    //     loop {
+
+    //     }
+    // });
+
+    // loop {
+    //     let a = 10;
+    //     println!("a = {}", a);
+    // }
 
-            if !KEY_PRESSED {
-                if CURRENT_KEY != -1 {
-                    println!("\rNote Off : {}s", sound.get_time());
-                    sound.set_frequency_output(0.0);
-                    sound.set_envelope_note_off();
-                    CURRENT_KEY = -1;
+    
+
+    keyboard::keyboard::start();
+
+    loop {
+        let mut key_pressed = false;
+        for k in 0 .. keyboard::keyboard::KEY_COUNT {
+            // White and black keys
+            if keyboard::keyboard::key_down(k) {
+                if current_key != k as i32 {
+                    sound.set_frequency_output(OCTAVE_BASE_FREQUENCY * num::pow(TWELVE_ROOT_OF_TWO, k));
+                    sound.set_envelop_note_on();
+                    eprintln!("\rNote On : {}s {}Hz", sound.get_time(), sound.get_frequency_output());
+                    current_key = k as i32;
                 }
+                key_pressed = true;
             }
         }
 
-        println!("Out of loop");
+        if !key_pressed && current_key != -1 {
+            eprintln!("\rNote Off : {}s", sound.get_time());
+            sound.set_frequency_output(0.0);
+            sound.set_envelope_note_off();
+            current_key = -1;
+        }
+
+        // Keys are polled, a few ms between passes is far below what anyone can hear
+        thread::sleep(Duration::from_millis(2));
     }
+
+    eprintln!("Out of loop");
 }
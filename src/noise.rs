@@ -2,36 +2,41 @@ pub mod noise {
 
     //extern crate condition_variable;
     extern crate num;
-    extern crate winapi;
 
+    use crate::backend::backend::{AudioBackend, BlockSignal, DeviceInfo, RenderCallback, SampleFormat, StreamFormat};
+    use crate::command::command::{Command, Param, TimedCommand};
     use crate::envelope::envelope::EnvelopeADSR;
-
-    use num::pow;
-    use num::Integer;
-
+    use crate::error::error::NoiseError;
+    use crate::convert::convert;
+    use crate::dither::dither::{Dither, Ditherer};
+    use crate::oscillator::oscillator::{Oscillator, Waveform};
+    use crate::source::source::{Effect, FunctionSource, ProcessContext, SoundSource, UserFunction};
+    use crate::voice::voice::Voice;
+    use crate::spsc::spsc::{self, Consumer, Producer};
+
+    use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
     use std::mem;
-    use std::ptr;
-    use std::sync::atomic::{AtomicBool, AtomicU32, AtomicPtr, Ordering};
-    use std::sync::{Mutex, Arc, Condvar, Weak};
+    use std::sync::{Arc, Mutex, MutexGuard};
     use std::thread;
+    use std::time::Duration;
     use std::vec::Vec;
     use std::clone::Clone;
 
-    use winapi::shared::minwindef;
-    use winapi::shared::mmreg;
-    use winapi::shared::winerror;
-    use winapi::shared::basetsd;
-    use winapi::shared::ntdef;
-    use winapi::um::mmeapi;
-    use winapi::um::mmsystem;
-
-    use std::f64::consts::PI;
+    // Commands that can be waiting for the audio thread at once
+    const COMMAND_QUEUE_CAPACITY: usize = 1024;
+    // Same for voice / effect changes
+    const CHANGE_QUEUE_CAPACITY: usize = 64;
+    // Room reserved up front, so adding a voice or effect never reallocates on the audio thread
+    const MAX_VOICES: usize = 16;
+    const MAX_EFFECTS: usize = 16;
 
     pub struct NoiseArgs {
         pub sample_rate: u32,
         pub channels: u32,
         pub blocks: u32,
         pub block_samples: u32,
+        pub sample_format: SampleFormat, // What blocks are encoded as for the device
+        pub dither: Dither,              // Applied when sample_format is I16
     }
 
     impl Default for NoiseArgs {
@@ -41,408 +46,794 @@ pub mod noise {
                 channels: 1,
                 blocks: 8,
                 block_samples: 512,
+                sample_format: SampleFormat::I16,
+                dither: Dither::None,
             }
         }
     }
 
-    trait Noise {
-        fn user_process(&self, delta_time: f64) -> f64;
+    // The patch played until a SoundSource or user function is set
+    struct DefaultPatch {
+        envelope: EnvelopeADSR,
+        oscillators: [(Oscillator, f64); 3], // With their frequency relative to the note
+        note_frequency: f64,                 // Of the last note on
+    }
+
+    // Voice and effect edits. The audio thread applies them between blocks instead of the control
+    // side locking the synth, and sends whatever they replaced back to be dropped over there.
+    enum Change {
+        Voices(Vec<Voice>),
+        AddVoice(Voice),
+        Effects(Vec<Box<dyn Effect>>),
+        AddEffect(Box<dyn Effect>),
+    }
+
+    // Sound generation state, only touched by whichever thread renders (the lock is never contended
+    // while running). The control side talks to it through the command and change queues.
+    struct Synth {
+        sample_rate: u32,
+        channels: u32,
+        frame: u64, // Frames rendered since start, the engine clock. Time is derived from it, never accumulated.
+
+        frequency_output: f64,
+        volume: f64,
+        voices: Vec<Voice>,            // Layered, every voice gets every note
+        effects: Vec<Box<dyn Effect>>, // Run in order on every block
+        mix: Vec<f32>, // Block the source renders into, effects work on it in place
+        ditherer: Ditherer,
+
+        commands: Consumer<TimedCommand>,
+        pending: Vec<TimedCommand>, // Drained commands not due yet, latest first
+        changes: Consumer<Change>,
+        garbage: Producer<Change>, // What changes replaced, on its way back to the control side
+    }
+
+    // Everything the audio thread (or a callback driven backend) shares with the control side
+    struct Engine {
+        synth: Mutex<Synth>,
+        backend: Mutex<Box<dyn AudioBackend>>,
+        commands: Mutex<Producer<TimedCommand>>, // Only serialises control threads, the audio side never locks it
+
+        sample_format: SampleFormat,
+
+        sample_rate: u32,
+        frame: AtomicU64,            // Synth::frame at the end of the last block
+        frequency_output: AtomicU64, // f64 bits of the last requested frequency
+
+        ready: AtomicBool,  // main_thread (or the backend callback) is generating
+        paused: AtomicBool,
+        block_current: AtomicU32, // Next block main_thread fills. Kept over pause / resume, the device still holds the others.
+    }
+
+    // Cheap, cloneable control side of a NoiseMaker, usable from any thread
+    #[derive(Clone)]
+    pub struct NoiseHandle {
+        engine: Arc<Engine>,
     }
 
     pub struct NoiseMaker {
-        user_function: AtomicPtr<fn(f64) -> f64>,
+        engine: Arc<Engine>,
+
         sample_rate: u32,
         channels: u32,
         block_count: u32,
         block_samples: u32,
-        block_current: u32,
-        block_memory: Vec<i16>,
+        sample_format: SampleFormat,
+        block_signal: Arc<BlockSignal>,
 
-        condition_variable: Condvar,
-        mux_block_not_zero: Mutex<i16>,
+        output_device: Option<String>,
+        thread: Option<thread::JoinHandle<()>>,
 
-        wave_headers: Vec<mmsystem::WAVEHDR>, // Array of headers
-        hw_device: mmsystem::HWAVEOUT,        // Chosen device
+        changes: Producer<Change>,
+        garbage: Consumer<Change>,
+        voice_count: usize, // What the synth has once the queued changes are applied
+        effect_count: usize,
+    }
 
-        ready: AtomicBool,
-        block_free: AtomicU32,
-        thread: thread::JoinHandle<()>,
-        global_time: f64,
+    impl Drop for NoiseMaker {
 
-        frequency_output: f64,
-        envelop: AtomicPtr<EnvelopeADSR>
+        fn drop(&mut self) { let _ = self.destroy(); }
     }
 
-    impl Noise for NoiseMaker {
-        fn user_process(&self, _: f64) -> f64 { 
-            return 0.0;
+    impl DefaultPatch {
+        fn new() -> Self {
+            return Self {
+                envelope: EnvelopeADSR::new(),
+                oscillators: [
+                    (Oscillator::new(Waveform::SquareBandLimited, 0.0, 0.4), 1.0),
+                    (Oscillator::new(Waveform::SawBandLimited, 0.0, 0.4), 0.5),
+                    (Oscillator::new(Waveform::SquareBandLimited, 0.0, 0.4), 1.0),
+                ],
+                note_frequency: 0.0,
+            };
         }
     }
 
-    impl Drop for NoiseMaker {
-     
-        fn drop(&mut self) { self.destroy(); }
+    impl SoundSource for DefaultPatch {
+        // Mono
+        fn process(&mut self, out: &mut [f32], ctx: &ProcessContext) {
+            // The keyboard sets the frequency to 0 at note off, the release keeps the note's pitch
+            let frequency = if ctx.frequency != 0.0 { ctx.frequency } else { self.note_frequency };
+            out.iter_mut().for_each(|sample| *sample = 0.0);
+            for (oscillator, ratio) in self.oscillators.iter_mut() {
+                oscillator.frequency = frequency * *ratio;
+                oscillator.render_add(out, ctx.sample_rate);
+            }
+
+            for (n, sample) in out.iter_mut().enumerate() {
+                let time = (ctx.frame + n as u64) as f64 / ctx.sample_rate as f64;
+                *sample *= self.envelope.get_amplitude(time) as f32;
+            }
+        }
+
+        fn note_on(&mut self, frequency: f64, time: f64) {
+            self.note_frequency = frequency;
+            self.envelope.set_note_on(time);
+        }
+
+        fn note_off(&mut self, time: f64) {
+            self.envelope.set_note_off(time);
+        }
+
+        fn restart(&mut self) {
+            *self = Self::new();
+        }
     }
 
-    static mut ATOMIC_PTR: AtomicPtr<NoiseMaker> = AtomicPtr::<NoiseMaker>::new(ptr::null_mut());
-    //static mut ENVELOPE: AtomicPtr<EnvelopeADSR> = AtomicPtr::<EnvelopeADSR>::new(ptr::null_mut());
-    //static mut FREQUENCY_OUTPUT: AtomicPtr<f64> = AtomicPtr::<f64>::new(ptr::null_mut());
+    impl Synth {
+        // Applies the voice / effect changes queued by the control side
+        fn apply_changes(&mut self) {
+            while let Some(change) = self.changes.pop() {
+                let replaced = match change {
+                    Change::Voices(voices) => Change::Voices(mem::replace(&mut self.voices, voices)),
+                    Change::Effects(effects) => Change::Effects(mem::replace(&mut self.effects, effects)),
+                    Change::AddVoice(voice) => {
+                        self.voices.push(voice);
+                        continue;
+                    }
+                    Change::AddEffect(effect) => {
+                        self.effects.push(effect);
+                        continue;
+                    }
+                };
+                // The control side empties it before every change it sends, so this only drops here
+                // if more changes were queued than the garbage queue holds
+                let _ = self.garbage.push(replaced);
+            }
+        }
+
+        // Moves what is queued by the control side into `pending`, ordered by time. `pending` never
+        // grows on the audio thread: with it full (more than COMMAND_QUEUE_CAPACITY commands scheduled
+        // ahead), the rest waits in the queue until earlier ones fell due.
+        fn drain_commands(&mut self) {
+            while self.pending.len() < self.pending.capacity() {
+                let command = match self.commands.pop() {
+                    Some(command) => command,
+                    None => break,
+                };
+                // Equal times keep their send order
+                let position = self.pending.iter().position(|x| x.time <= command.time).unwrap_or(self.pending.len());
+                self.pending.insert(position, command);
+            }
+        }
+
+        fn time(&self) -> f64 {
+            return self.frame as f64 / self.sample_rate as f64;
+        }
+
+        // Frames from now until the next pending command is due, if any.
+        // A command is due at the first frame less than half a sample before its time.
+        fn frames_until_command(&self) -> Option<usize> {
+            return self.pending.last().map(|next| {
+                let due = f64::max(0.0, f64::ceil(next.time * self.sample_rate as f64 - 0.5)) as u64;
+                due.saturating_sub(self.frame) as usize
+            });
+        }
+
+        // Applies the commands that are due at the current frame
+        fn apply_commands(&mut self) {
+            while self.frames_until_command() == Some(0) {
+                let command = self.pending.pop().unwrap().command;
+                match command {
+                    Command::NoteOn { frequency } => {
+                        self.frequency_output = frequency;
+                        let time = self.time();
+                        self.voices.iter_mut().for_each(|voice| voice.source().note_on(frequency, time));
+                    }
+                    Command::NoteOff => {
+                        let time = self.time();
+                        self.voices.iter_mut().for_each(|voice| voice.source().note_off(time));
+                    }
+                    Command::SetPan { voice, pan } => {
+                        if let Some(voice) = self.voices.get_mut(voice) {
+                            voice.set_pan(pan);
+                        }
+                    }
+                    Command::SetParam { param: Param::Frequency, value } => self.frequency_output = value,
+                    Command::SetParam { param: Param::Volume, value } => self.volume = value,
+                }
+            }
+        }
+
+        fn context(&self) -> ProcessContext {
+            return ProcessContext {
+                time: self.time(),
+                frame: self.frame,
+                channel: 0,
+                channels: self.channels,
+                sample_rate: self.sample_rate,
+                frequency: self.frequency_output,
+                output: 0.0,
+            };
+        }
+
+        // Renders the next `frames` frames into `mix`, in f32 from start to end
+        fn render(&mut self, frames: usize) {
+            self.apply_changes();
+            self.drain_commands();
+
+            let channels = self.channels as usize;
+            let block_ctx = self.context();
+            self.mix.resize(frames * channels, 0.0);
+
+            // Render the block in pieces, commands take effect between them
+            let mut frame = 0;
+            while frame < frames {
+                self.apply_commands();
+                let end = match self.frames_until_command() {
+                    Some(n) => usize::min(frame + n, frames),
+                    None => frames,
+                };
+
+                let ctx = self.context();
+                let segment = &mut self.mix[frame * channels .. end * channels];
+                segment.iter_mut().for_each(|sample| *sample = 0.0);
+                for voice in self.voices.iter_mut() {
+                    voice.render(segment, &ctx);
+                }
+                let volume = self.volume as f32;
+                segment.iter_mut().for_each(|sample| *sample *= volume);
+
+                self.frame += (end - frame) as u64;
+                frame = end;
+            }
+
+            for effect in self.effects.iter_mut() {
+                effect.process(&mut self.mix, &block_ctx);
+            }
+        }
+    }
+
+    impl Engine {
+        fn synth(&self) -> MutexGuard<'_, Synth> {
+            return self.synth.lock().unwrap();
+        }
+
+        // Renders the next out.len() / sample_format.bytes() interleaved samples, encodes them
+        // for the device and publishes the new engine time
+        fn fill_samples(&self, out: &mut [u8]) {
+            let mut guard = self.synth();
+            let synth = &mut *guard;
+            let frames = out.len() / self.sample_format.bytes() / synth.channels as usize;
+            synth.render(frames);
+            synth.ditherer.process(&mut synth.mix, self.sample_format);
+            convert::encode(self.sample_format, &synth.mix, out);
+            self.frame.store(synth.frame, Ordering::SeqCst);
+        }
+
+        // Float output for callback driven backends, no encoding needed
+        fn fill_float(&self, out: &mut [f32]) {
+            let mut synth = self.synth();
+            let frames = out.len() / synth.channels as usize;
+            synth.render(frames);
+            out.copy_from_slice(&synth.mix);
+            self.frame.store(synth.frame, Ordering::SeqCst);
+        }
+
+        // Back to time 0 with sources and effects as they were created, so a restart renders the
+        // same samples again. Parameters (frequency, volume, pan) keep their last value, and commands
+        // queued meanwhile are kept: send() stamps them with 0 while stopped.
+        fn reset_time(&self) {
+            let mut synth = self.synth();
+            synth.frame = 0;
+            synth.ditherer.reset();
+            synth.voices.iter_mut().for_each(|voice| voice.source().restart());
+            synth.effects.iter_mut().for_each(|effect| effect.restart());
+            self.frame.store(0, Ordering::SeqCst);
+        }
+
+        // Drops everything scheduled against the current timeline
+        fn discard_commands(&self) {
+            let mut synth = self.synth();
+            synth.pending.clear();
+            while synth.commands.pop().is_some() {}
+        }
+
+        fn main_thread(&self, signal: Arc<BlockSignal>, block_count: u32, block_samples: u32) {
+            eprintln!("Main thread running...");
+            let mut block_memory: Vec<u8> = vec![0; block_samples as usize * self.sample_format.bytes()];
+            let mut block_current: u32 = self.block_current.load(Ordering::SeqCst);
+
+            while self.ready.load(Ordering::SeqCst) {
+                // Wait for block to become available, then take it
+                if !signal.acquire() {
+                    break;
+                }
+                self.process_block(block_current as usize, &mut block_memory);
+                block_current += 1;
+                block_current %= block_count;
+                self.block_current.store(block_current, Ordering::SeqCst);
+            }
+
+            // The backend gave up on the device (BlockSignal::fail), nothing is generating any more
+            if signal.has_failed() {
+                eprintln!("Output device stopped taking blocks");
+                self.ready.store(false, Ordering::SeqCst);
+            }
+        }
+
+        // Fills `block` and hands it to the backend. The synth is not held while the backend
+        // blocks, so control calls only ever wait for one block to be computed.
+        fn process_block(&self, block: usize, block_memory: &mut [u8]) {
+            self.fill_samples(block_memory);
+
+            // Send block to sound devices
+            self.backend.lock().unwrap().submit(block, block_memory);
+        }
+    }
+
+    impl NoiseHandle {
+        // Queues `command` for the start of the next block. Sent before start() / render(),
+        // it applies from the first sample (stop() throws away whatever is still queued).
+        pub fn send(&self, command: Command) -> Result<(), NoiseError> {
+            return self.send_at(self.get_time(), command);
+        }
+
+        // Queues `command` to take effect at engine time `time`, down to the sample
+        pub fn send_at(&self, time: f64, command: Command) -> Result<(), NoiseError> {
+            let timed = TimedCommand { time: time, command: command };
+            self.engine.commands.lock().unwrap().push(timed).map_err(|_| NoiseError::QueueFull)?;
+
+            // Only what the audio thread will actually get
+            if let Command::NoteOn { frequency } | Command::SetParam { param: Param::Frequency, value: frequency } = command {
+                self.engine.frequency_output.store(frequency.to_bits(), Ordering::SeqCst);
+            }
+            return Ok(());
+        }
+
+        pub fn set_frequency_output(&self, frequency: f64) {
+            if let Err(e) = self.send(Command::SetParam { param: Param::Frequency, value: frequency }) {
+                eprintln!("Frequency change dropped: {}", e);
+            }
+        }
+
+        // Last frequency sent, the audio thread picks it up at its next block
+        pub fn get_frequency_output(&self) -> f64 {
+            return f64::from_bits(self.engine.frequency_output.load(Ordering::SeqCst));
+        }
+
+        pub fn set_envelop_note_on(&self) {
+            eprintln!("Set envelop note on");
+            if let Err(e) = self.send(Command::NoteOn { frequency: self.get_frequency_output() }) {
+                eprintln!("Note on dropped: {}", e);
+            }
+        }
+
+        pub fn set_envelope_note_off(&self) {
+            eprintln!("Set envelop note off");
+            if let Err(e) = self.send(Command::NoteOff) {
+                eprintln!("Note off dropped: {}", e);
+            }
+        }
+
+        // Engine time at the end of the last rendered block
+        pub fn get_time(&self) -> f64 {
+            return self.get_frame() as f64 / self.engine.sample_rate as f64;
+        }
+
+        // Frames rendered since start, get_time() without rounding
+        pub fn get_frame(&self) -> u64 {
+            return self.engine.frame.load(Ordering::SeqCst);
+        }
+
+        pub fn is_running(&self) -> bool {
+            return self.engine.ready.load(Ordering::SeqCst);
+        }
+    }
 
     impl NoiseMaker {
-        pub fn new(args: NoiseArgs) -> Self {
+        // Plays through the platform's sound card backend, fails when the build has none
+        pub fn new(args: NoiseArgs) -> Result<Self, NoiseError> {
+            let backend = crate::backend::backend::default_backend()?;
+            return Ok(Self::with_backend(args, backend));
+        }
+
+        pub fn with_backend(args: NoiseArgs, backend: Box<dyn AudioBackend>) -> Self {
+            let (producer, consumer) = spsc::channel(COMMAND_QUEUE_CAPACITY);
+            let (changes, synth_changes) = spsc::channel(CHANGE_QUEUE_CAPACITY);
+            let (synth_garbage, garbage) = spsc::channel(CHANGE_QUEUE_CAPACITY);
+            let mut voices = Vec::with_capacity(MAX_VOICES);
+            voices.push(Voice::new(Box::new(DefaultPatch::new()), 0.0));
+            let engine = Engine {
+                synth: Mutex::new(Synth {
+                    sample_rate: args.sample_rate,
+                    channels: args.channels,
+                    frame: 0,
+                    frequency_output: 0.0,
+                    volume: 1.0,
+                    voices: voices,
+                    effects: Vec::with_capacity(MAX_EFFECTS),
+                    mix: Vec::with_capacity(args.block_samples as usize),
+                    ditherer: Ditherer::new(args.dither, args.channels),
+                    commands: consumer,
+                    pending: Vec::with_capacity(COMMAND_QUEUE_CAPACITY),
+                    changes: synth_changes,
+                    garbage: synth_garbage,
+                }),
+                backend: Mutex::new(backend),
+                commands: Mutex::new(producer),
+                sample_format: args.sample_format,
+                sample_rate: args.sample_rate,
+                frame: AtomicU64::new(0),
+                frequency_output: AtomicU64::new(0.0f64.to_bits()),
+                ready: AtomicBool::new(false),
+                paused: AtomicBool::new(false),
+                block_current: AtomicU32::new(0),
+            };
+
             let obj = Self {
-                user_function: AtomicPtr::<fn(f64) -> f64>::new(ptr::null_mut()),
+                engine: Arc::new(engine),
+
                 sample_rate: args.sample_rate,
                 channels: args.channels,
-
                 block_count: args.blocks,
                 block_samples: args.block_samples,
-                block_current: 0,
-                block_memory: Vec::<i16>::new(),
-                block_free: AtomicU32::new(args.blocks),
-
-                condition_variable: Condvar::new(),
-                mux_block_not_zero: Mutex::new(0),
+                sample_format: args.sample_format,
+                block_signal: Arc::new(BlockSignal::new(args.blocks)),
 
-                wave_headers: Vec::<mmsystem::WAVEHDR>::new(),
-                hw_device: ptr::null_mut(),
+                output_device: None,
+                thread: None,
 
-                ready: AtomicBool::new(false),
-                thread: thread::spawn(|| {}),
-                global_time: 0.0,
-
-                frequency_output: 0.0,
-                envelop: AtomicPtr::new(&mut EnvelopeADSR::new()),
+                changes: changes,
+                garbage: garbage,
+                voice_count: 1,
+                effect_count: 0,
             };
 
             return obj;
         }
 
+        // Control side that can be handed to other threads, stays valid after the NoiseMaker is gone
+        pub fn handle(&self) -> NoiseHandle {
+            return NoiseHandle { engine: Arc::clone(&self.engine) };
+        }
+
         pub fn set_frequency_output(&mut self, frequency: f64) {
-            self.frequency_output = frequency;
+            self.handle().set_frequency_output(frequency);
         }
 
         pub fn get_frequency_output(&self) -> f64 {
-            return self.frequency_output;
+            return self.handle().get_frequency_output();
         }
 
         pub fn set_envelop_note_on(&mut self) {
-            println!("Set envelop note on");
-            let val = self.envelop.load(Ordering::SeqCst);
-            unsafe {
-                (*val).set_note_on(self.get_time());
-            }
+            self.handle().set_envelop_note_on();
         }
 
         pub fn set_envelope_note_off(&mut self) {
-            println!("Set envelop note off");
-            let val = self.envelop.load(Ordering::SeqCst);
-            unsafe {
-                (*val).set_note_off(self.get_time());
-            }
+            self.handle().set_envelope_note_off();
         }
 
-        pub unsafe fn create(&mut self, output_device: String) -> bool {
-            let devices: Vec<String> = Self::enumerate();
-            let mut devices_iter = devices.iter();
-            println!("Chosen output device {}", output_device);
-            let find_res = devices_iter.position(|x| x.eq(&output_device));
+        pub fn send(&self, command: Command) -> Result<(), NoiseError> {
+            return self.handle().send(command);
+        }
+
+        pub fn send_at(&self, time: f64, command: Command) -> Result<(), NoiseError> {
+            return self.handle().send_at(time, command);
+        }
 
-            let device_id: usize = match find_res {
-                Some(x) => x,
-                None => 0,
+        // Opens `output_device` (a DeviceInfo::name from enumerate()) and starts generating sound
+        pub fn create(&mut self, output_device: String) -> Result<(), NoiseError> {
+            if self.output_device.is_some() {
+                return Err(NoiseError::AlreadyRunning);
+            }
+
+            self.output_device = Some(output_device);
+            if let Err(e) = self.start() {
+                self.output_device = None;
+                return Err(e);
+            }
+            return Ok(());
+        }
+
+        // (Re)opens the device chosen in create() and starts generating from time 0
+        pub fn start(&mut self) -> Result<(), NoiseError> {
+            if self.engine.ready.load(Ordering::SeqCst) || self.engine.paused.load(Ordering::SeqCst) {
+                return Err(NoiseError::AlreadyRunning);
+            }
+            let output_device = match self.output_device.clone() {
+                Some(output_device) => output_device,
+                None => return Err(NoiseError::BackendError("start() called before create()".to_string())),
             };
 
-            ATOMIC_PTR = AtomicPtr::new(self);
-            //ENVELOPE = AtomicPtr::new(&mut EnvelopeADSR::new());
-
-            println!("Device id {} | devices len {}", device_id, devices.len());
-            if device_id < devices.len() {
-                println!("Initializing wave_format");
-                // Device is available
-                let mut wave_format: mmreg::WAVEFORMATEX = mem::zeroed::<mmreg::WAVEFORMATEX>();
-                wave_format.wFormatTag = mmreg::WAVE_FORMAT_PCM;
-                wave_format.nSamplesPerSec = self.sample_rate;
-                wave_format.wBitsPerSample = (mem::size_of::<u16>() * 8) as u16;
-                wave_format.nChannels = self.channels as u16;
-                wave_format.nBlockAlign = (wave_format.wBitsPerSample / 8) * wave_format.nChannels;
-                wave_format.nAvgBytesPerSec = wave_format.nSamplesPerSec * wave_format.nBlockAlign as u32;
-                wave_format.cbSize = 0;
-
-                // Open Device if valid
-                println!("Opening device (waveOutOpen)");
-                let selfptr = ATOMIC_PTR.load(Ordering::SeqCst) as basetsd::DWORD_PTR;
-                let callback_func_ptr = Self::wave_out_proc_wrapper as basetsd::DWORD_PTR;
-                println!("Pointer to self as basetsd::DWORD_PTR = {}", selfptr);
-                println!("Pointer to self as u32 = {}", ATOMIC_PTR.load(Ordering::SeqCst) as u32);
-                if mmeapi::waveOutOpen(&mut self.hw_device, device_id as u32, &wave_format, callback_func_ptr, 
-                    selfptr, mmsystem::CALLBACK_FUNCTION) != winerror::S_OK as u32
-                {
-                    println!("Failed to open");
-                    return self.destroy();
-                }
+            self.engine.reset_time();
+
+            if self.engine.backend.lock().unwrap().is_callback_driven() {
+                return self.create_callback(&output_device);
+            }
+
+            self.open_device(&output_device)?;
+            self.spawn_thread();
+            return Ok(());
+        }
+
+        // Stops generating, joins main_thread and closes the device. start() opens it again.
+        pub fn stop(&mut self) -> Result<(), NoiseError> {
+            self.join_thread();
+            self.engine.ready.store(false, Ordering::SeqCst);
+            self.engine.paused.store(false, Ordering::SeqCst);
+            self.engine.backend.lock().unwrap().close();
+            self.engine.discard_commands();
+            self.engine.reset_time();
+            while self.garbage.pop().is_some() {}
+            return Ok(());
+        }
 
-                println!("Wave format initialized!");
+        // Holds playback with the device kept open, resume() continues where it left off
+        pub fn pause(&mut self) -> Result<(), NoiseError> {
+            if !self.engine.ready.load(Ordering::SeqCst) {
+                return Ok(());
             }
+            self.engine.paused.store(true, Ordering::SeqCst);
 
-            // Allocate Wave | Block memory
-            println!("Allocating wave (block count = {}, block_samples = {})", self.block_count, self.block_samples);
-            let block_memory_len = self.block_count * self.block_samples;
-            self.block_memory = vec![0; block_memory_len as usize];
-            println!("block memory len = {} | ({})", self.block_memory.len(), block_memory_len);
-            println!("Reserving memory for Wave headers");
-            self.wave_headers = vec![mem::zeroed(); self.block_count as usize];
-            println!("Wave headers len = {}", self.wave_headers.len());
+            // Callback driven backends keep pulling, they get silence until resume()
+            if self.engine.backend.lock().unwrap().is_callback_driven() {
+                return Ok(());
+            }
+            self.join_thread();
+            self.engine.backend.lock().unwrap().pause();
+            return Ok(());
+        }
 
-            // Link headers to block memory
-            println!("Linking headers to block memory");
-            for n in 0..self.block_count {
-                let dw_buffer_length = self.block_samples * mem::size_of::<u16>() as u32;
-                let lp_data = ((self.block_memory).as_ptr().offset((n * self.block_samples) as isize)) as ntdef::LPSTR;
+        pub fn resume(&mut self) -> Result<(), NoiseError> {
+            if !self.engine.paused.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            self.engine.paused.store(false, Ordering::SeqCst);
 
-                println!("Linking {}-th header | dwBufferLength = {} | wave headers len = {}", n, dw_buffer_length, self.wave_headers.len());
-                self.wave_headers[n as usize].dwBufferLength = dw_buffer_length;
-                self.wave_headers[n as usize].lpData = lp_data;
+            if self.engine.backend.lock().unwrap().is_callback_driven() {
+                return Ok(());
             }
+            self.engine.backend.lock().unwrap().resume();
+            self.spawn_thread();
+            return Ok(());
+        }
 
-            *self.ready.get_mut() = true;
+        // Renders `duration` seconds through the backend on the calling thread, as fast as the
+        // backend accepts blocks. Meant for offline backends such as WavBackend.
+        pub fn render(&mut self, output_device: String, duration: f64) -> Result<(), NoiseError> {
+            if self.output_device.is_some() {
+                return Err(NoiseError::AlreadyRunning);
+            }
+            self.open_device(&output_device)?;
 
-            println!("atomic_ptr before thread = {}", ATOMIC_PTR.load(Ordering::SeqCst) as basetsd::DWORD_PTR);
+            let block_time = self.block_samples as f64 / self.sample_rate as f64;
+            let block_total = f64::ceil(duration / block_time) as u64;
+            eprintln!("Rendering {} blocks ({}s)", block_total, duration);
+
+            self.engine.reset_time();
+            let mut block_memory: Vec<u8> = vec![0; self.block_samples as usize * self.sample_format.bytes()];
+            for n in 0 .. block_total {
+                if !self.block_signal.acquire() {
+                    break;
+                }
+                self.engine.process_block((n % self.block_count as u64) as usize, &mut block_memory);
+            }
+
+            self.engine.backend.lock().unwrap().close();
+            if self.block_signal.has_failed() {
+                return Err(NoiseError::BackendError(format!("{} stopped taking blocks after {}s", output_device, self.get_time())));
+            }
+            return Ok(());
+        }
+
+        fn spawn_thread(&mut self) {
+            self.engine.ready.store(true, Ordering::SeqCst);
 
             //? Starting thread
-            self.thread = thread::spawn(move || {
-                println!("Main thread running!");
-                let noise = ATOMIC_PTR.load(Ordering::SeqCst);
-                println!("atomic_ptr = {}", noise as basetsd::DWORD_PTR);
-                (*noise).main_thread();
-                println!("Thread passed");
+            let engine = Arc::clone(&self.engine);
+            let signal = Arc::clone(&self.block_signal);
+            let (block_count, block_samples) = (self.block_count, self.block_samples);
+            self.thread = Some(thread::spawn(move || {
+                eprintln!("Main thread running!");
+                engine.main_thread(signal, block_count, block_samples);
+                eprintln!("Thread passed");
+            }));
+
+            eprintln!("Thread started!");
+        }
+
+        // Makes main_thread leave its loop, even when it is waiting for the device, and waits for it
+        fn join_thread(&mut self) {
+            let thread = match self.thread.take() {
+                Some(thread) => thread,
+                None => return,
+            };
+            self.engine.ready.store(false, Ordering::SeqCst);
+            self.block_signal.interrupt();
+            if thread.join().is_err() {
+                eprintln!("Main thread panicked");
+            }
+            self.block_signal.clear_interrupt();
+        }
+
+        // The backend runs its own realtime thread and pulls samples from there, so no main_thread
+        fn create_callback(&mut self, output_device: &str) -> Result<(), NoiseError> {
+            let device_id = self.find_device(output_device)?;
+
+            let engine = Arc::clone(&self.engine);
+            let render: RenderCallback = Box::new(move |out: &mut [f32]| {
+                if engine.paused.load(Ordering::SeqCst) {
+                    out.iter_mut().for_each(|sample| *sample = 0.0);
+                } else {
+                    engine.fill_float(out);
+                }
             });
+            let format = self.stream_format();
+            self.engine.backend.lock().unwrap().open_callback(device_id, format, render)?;
 
-            println!("Thread started!");
+            self.engine.ready.store(true, Ordering::SeqCst);
+            return Ok(());
+        }
+
+        fn stream_format(&self) -> StreamFormat {
+            return StreamFormat {
+                sample_rate: self.sample_rate,
+                channels: self.channels,
+                blocks: self.block_count,
+                block_samples: self.block_samples,
+                sample_format: self.sample_format,
+            };
+        }
 
-            //? Start
+        // Id of `output_device` in enumerate(), checked against the stream format we are going to ask for
+        fn find_device(&self, output_device: &str) -> Result<usize, NoiseError> {
+            // Blocks hold whole frames, a partial one would shift the channel interleave every block
+            if self.channels == 0 || self.block_samples % self.channels != 0 {
+                return Err(NoiseError::FormatUnsupported(format!("{} samples per block do not make whole frames of {} channels", self.block_samples, self.channels)));
+            }
 
-            println!("Created new mutex");
-            let mutex = Mutex::new(&self.mux_block_not_zero);
-            let started = mutex.lock().unwrap();
+            let devices: Vec<DeviceInfo> = self.enumerate();
+            eprintln!("Chosen output device {}", output_device);
 
-            println!("Notify one");
-            self.condition_variable.notify_one();
+            let device = match devices.iter().find(|x| x.name == output_device) {
+                Some(device) => device,
+                None => return Err(NoiseError::DeviceNotFound(output_device.to_string())),
+            };
+            if !device.probed {
+                return Err(NoiseError::BackendError(format!("{} is busy or could not be queried", device.name)));
+            }
+            if !device.supports_sample_rate(self.sample_rate) {
+                return Err(NoiseError::FormatUnsupported(format!("{} does not support {}Hz (supported: {:?})", device.name, self.sample_rate, device.sample_rates)));
+            }
+            if !device.supports_channels(self.channels) {
+                return Err(NoiseError::FormatUnsupported(format!("{} does not support {} channels (supported: {:?})", device.name, self.channels, device.channels)));
+            }
+            if !device.supports_sample_format(self.sample_format) && !self.engine.backend.lock().unwrap().is_callback_driven() {
+                return Err(NoiseError::FormatUnsupported(format!("{} does not support {} (supported: {:?})", device.name, self.sample_format.name(), device.sample_formats)));
+            }
 
-            return true;
+            eprintln!("Device id {} | devices len {}", device.id, devices.len());
+            return Ok(device.id);
         }
 
-        fn destroy(&self) -> bool {
-            return false;
+        fn open_device(&mut self, output_device: &str) -> Result<(), NoiseError> {
+            let device_id = self.find_device(output_device)?;
+
+            // Every block starts out free, whatever the previous device still held when it was closed
+            self.block_signal = Arc::new(BlockSignal::new(self.block_count));
+            self.engine.block_current.store(0, Ordering::SeqCst);
+            let format = self.stream_format();
+            self.engine.backend.lock().unwrap().open(device_id, format, Arc::clone(&self.block_signal))?;
+
+            eprintln!("Device opened (block count = {}, block_samples = {})", self.block_count, self.block_samples);
+            return Ok(());
         }
 
-        // fn stop(&mut self) {
-        //     *self.ready.get_mut() = false;
-        //     self.thread.join().unwrap();
-        // }
+        // Stops everything and forgets the device, the NoiseMaker can be create()d again afterwards
+        pub fn destroy(&mut self) -> Result<(), NoiseError> {
+            self.stop()?;
+            self.output_device = None;
+            return Ok(());
+        }
 
         pub fn get_time(&self) -> f64 {
-            return self.global_time;
-        }
-
-        fn oscillate(&self, hertz: f64, current_time: f64, osc_type: i32) -> f64 {
-            return match osc_type {
-                0 => f64::sin(hz_to_angular(hertz) * current_time), // Sine wave
-                1 => if f64::sin(hz_to_angular(hertz) * current_time) > 0.0 { 1.0 } else { -1.0 }, // Square wave
-                2 => f64::asin(f64::sin(hz_to_angular(hertz) * current_time)) * (2.0 / PI), // Triangle wave
-                3 => { // Saw wave (analog / warm / slow)
-                    let mut output = 0.0;
-                    for n in 1 .. 100 {
-                        output += (f64::sin(n as f64 * hz_to_angular(hertz) * current_time)) / n as f64;
-                    }
-                    return output * (2.0 / PI);
-                },
-                4 => (2.0 / PI) * (hertz * PI * (current_time % (1.0 / hertz)) - (PI / 2.0)), // Saw wave (optimised / harsh / fast)
-                _ => 0.0
-            }
-        }
-
-        unsafe fn default_make_noise(&self, current_time: f64) -> f64 {
-            //return 0.5 * f64::sin(self.frequency_output * 2 as f64 * PI * current_time);
-
-            //println!("Freq output = {} | oscillate = {}", self.frequency_output, self.oscillate(self.frequency_output * 0.5, current_time, 3));
-            //let envelope = *self.envelop.load(Ordering::SeqCst);
-            let output = self.oscillate(self.frequency_output, current_time, 1) 
-                + self.oscillate(self.frequency_output * 0.5, current_time, 4)
-                + self.oscillate(self.frequency_output * 1.0, current_time, 1);
-            //let output = envelope.get_amplitude(current_time) *
-            //(
-            //    self.oscillate(self.frequency_output * 0.5, current_time, 3) + 
-            //    self.oscillate(self.frequency_output * 1.0, current_time, 1)
-            //);
-            //println!("output = {} | Freq output = {} | oscillate = {} | amplitude = {}", output, self.frequency_output, self.oscillate(self.frequency_output * 0.5, current_time, 3), 
-            //(*envelope).get_amplitude(current_time));
-            //println!("output = {}", output);
-            //return output * 0.4;
-            return output * 0.4;
-        }
-
-        pub fn main_thread(&mut self) -> () {
-            println!("Main thread running...");
-            self.global_time = 0.0;
-            let time_step: f64 = 1.0 / self.sample_rate as f64;
-            
-            let max_sample: i32 = pow(2, (std::mem::size_of::<u16>() * 8) - 1) - 1;
-            
-            let dmax_sample: f64 = max_sample as f64;
-            let mut previous_sample = 0;
-            
-            //println!("Time step = {}, Max sample = {}, Dmax sample = {}", time_step, max_sample, dmax_sample);
-            while self.ready.load(Ordering::SeqCst) {
-                //println!("main thread ready...");
-                // Wait for block to become available
-                if self.block_free.load(Ordering::SeqCst) == 0 {
-                    //println!("Waiting for block to become available");
-                    let mutex = Mutex::new(&self.mux_block_not_zero);
-                    let started = mutex.lock().unwrap();
-                    self.condition_variable.wait(started).unwrap();
-                }
-                //println!("block is here");
-                // Block is here, so use it
-                // todo: fix thread '<unnamed>' panicked at 'attempt to subtract with overflow', src\noise.rs:227:17
-                *self.block_free.get_mut() -= 1;
-                //println!("prepare block for processing");
-                // Prepare block for processing
-                if self.wave_headers[self.block_current as usize].dwFlags & 0x00000002 != 0
-                {
-                    //println!("Waving out unprepared header");
-                    unsafe {
-                        mmeapi::waveOutUnprepareHeader(
-                            self.hw_device,
-                            &mut self.wave_headers[self.block_current as usize],
-                            mem::size_of::<mmsystem::WAVEHDR>() as u32,
-                        );
-                    }
-                }
-                //println!("new sample");
-                let mut new_sample: i16;
-                let current_block: u32 = self.block_current * self.block_samples;
-                //println!("block_samples = {}", self.block_samples);
-                for n in 0 .. self.block_samples {
-                    //println!("Processing block");
-                    // User process
-                    if self.user_function.load(Ordering::SeqCst) == ptr::null_mut() {
-                        //println!("User process");
-                        new_sample = (self.clip(self.user_process(self.global_time), 1.0) * dmax_sample) as i16;
-                    } else {
-                        unsafe {
-                            //println!("user function is loaded");
-                            let f = *self.user_function.load(Ordering::SeqCst);
-                            //println!("user function is loaded 2");
-                            //println!("f = {}", f as u32);
-
-                            //? This line triggers segfault when main loop is running with some code
-                            //new_sample = (self.clip((f)(self.global_time), 1.0) * dmax_sample) as i16;
-                            new_sample = (self.default_make_noise(self.global_time) * dmax_sample) as i16;
-                            //println!("user function is loaded 3");
-                        }
-                    }
+            return self.handle().get_time();
+        }
 
-                    //println!("block memory len = {}, current block = {}, n = {}, new_sample = {}", self.block_memory.len(), current_block, n, new_sample);
-                    self.block_memory[(current_block + n) as usize] = new_sample as i16;
-                    previous_sample = new_sample;
+        pub fn get_frame(&self) -> u64 {
+            return self.handle().get_frame();
+        }
 
-                    self.global_time += time_step;
-                    //println!("global_time is {} at n = {}", self.global_time, n);
-                }
+        pub fn enumerate(&self) -> Vec<DeviceInfo> {
+            return self.engine.backend.lock().unwrap().enumerate();
+        }
 
-                // Send block to sound devices
-                //println!("Sending block to sound devices");
-                unsafe {
-                    mmeapi::waveOutPrepareHeader(self.hw_device, &mut self.wave_headers[self.block_current as usize], mem::size_of::<mmsystem::WAVEHDR>() as u32);
-                    mmeapi::waveOutWrite(self.hw_device, &mut self.wave_headers[self.block_current as usize], mem::size_of::<mmsystem::WAVEHDR>() as u32);
-                }
-                //println!("Blocks send");
-                self.block_current += 1;
-                //println!("block_current = {} | new block_current = {} (block_count = {})", self.block_current, self.block_current % self.block_count, self.block_count);
-                self.block_current %= self.block_count;
-            }
-        }
-
-        pub fn wave_out_proc(
-            &mut self,
-            wave_out: mmsystem::HWAVEOUT,
-            msg: u32,
-            dw_param1: minwindef::DWORD,
-            dw_param2: minwindef::DWORD
-        ) {
-            //println!("Wave out proc");
-            if msg != mmsystem::WOM_DONE {
-                //println!("msg is not mmsystem::WOM_DONE!");
-                return;
-            } else {
-                //println!("msg is mmsystem::WOM_DONE");
-            }
-            //println!("msg = {}", msg);
-            unsafe {
-                let p = ATOMIC_PTR.load(Ordering::SeqCst);
-                //println!("hey = {}", p as u32);
-                *(*p).block_free.get_mut() += 1;
-                //println!("init mutex");
-                let mutex = Mutex::new(&(*p).mux_block_not_zero);
-                //println!("_started...");
-                let _started = mutex.lock().unwrap();
-                //println!("notifying...");
-                (*p).condition_variable.notify_one();
-            }
-        }
-
-        unsafe fn wave_out_proc_wrapper(
-            wave_out: mmsystem::HWAVEOUT,
-            msg: u32,
-            dw_instance: minwindef::DWORD,
-            dw_param1: minwindef::DWORD,
-            dw_param2: minwindef::DWORD,
-        ) {
-            //println!("Wave out process wrapper | wave_out = {} | msg = {} | dw_instance = {} | dw_param1 = {} | dw_param2 = {}", wave_out as u32, msg, dw_instance, 
-            //dw_param1 as u32, dw_param2 as u32);
-            //println!("casting dw_instance as NoiseMaker (dw_instance = {})", dw_instance);
-            let dw_instance_ptr: *mut NoiseMaker = dw_instance as *mut NoiseMaker;
-            //println!("Calling wave_out_proc");
-            (*dw_instance_ptr).wave_out_proc(wave_out, msg, dw_param1, dw_param2);
-        }
-
-        pub unsafe fn enumerate() -> Vec<String> {
-            let device_count: u32 = mmeapi::waveOutGetNumDevs();
-            let mut devices: Vec<String> = Vec::new();
-            let mut woc: mmsystem::WAVEOUTCAPSW = mem::zeroed::<mmsystem::WAVEOUTCAPSW>();
-
-            for n in 0..device_count {
-                if mmeapi::waveOutGetDevCapsW(
-                    n as usize,
-                    &mut woc,
-                    mem::size_of::<mmsystem::WAVEOUTCAPSW>() as u32,
-                ) == winerror::S_OK as u32
-                {
-                    // todo: test
-                    devices.push(String::from_utf16(&woc.szPname.to_owned()).unwrap());
-                }
+        pub fn get_latency(&self) -> Option<Duration> {
+            return self.engine.backend.lock().unwrap().latency();
+        }
+
+        // Queues `change` for the audio thread, dropping what the earlier ones replaced
+        fn send_change(&mut self, change: Change) -> Result<(), NoiseError> {
+            while self.garbage.pop().is_some() {}
+            return self.changes.push(change).map_err(|_| NoiseError::QueueFull);
+        }
+
+        // Plays `source` (centred) instead of all current voices, takes effect at the next block
+        pub fn set_source(&mut self, source: Box<dyn SoundSource>) -> Result<(), NoiseError> {
+            let mut voices = Vec::with_capacity(MAX_VOICES);
+            voices.push(Voice::new(source, 0.0));
+            self.send_change(Change::Voices(voices))?;
+            self.voice_count = 1;
+            return Ok(());
+        }
+
+        // Layers `source` on top of the current voices, returns its index for set_pan()
+        pub fn add_voice(&mut self, source: Box<dyn SoundSource>, pan: f64) -> Result<usize, NoiseError> {
+            if self.voice_count == MAX_VOICES {
+                return Err(NoiseError::LimitReached(format!("voices, {} at most", MAX_VOICES)));
             }
+            self.send_change(Change::AddVoice(Voice::new(source, pan)))?;
+            self.voice_count += 1;
+            return Ok(self.voice_count - 1);
+        }
+
+        pub fn set_pan(&self, voice: usize, pan: f64) -> Result<(), NoiseError> {
+            return self.send(Command::SetPan { voice: voice, pan: pan });
+        }
 
-            return devices;
+        // Replaces the default sound with `func`, takes effect at the next block
+        pub fn set_user_function(&mut self, func: UserFunction) -> Result<(), NoiseError> {
+            self.set_source(Box::new(FunctionSource::new(func)))?;
+            eprintln!("User function is set!");
+            return Ok(());
         }
 
-        pub fn set_user_function(&mut self, mut func: fn(f64) -> f64) {
-            //self.user_function = &mut func;
-            self.user_function.store(&mut func, Ordering::SeqCst);
-            println!("User function is set!");
+        // Back to the default sound
+        pub fn clear_user_function(&mut self) -> Result<(), NoiseError> {
+            return self.set_source(Box::new(DefaultPatch::new()));
+        }
+
+        // Appends `effect` to the chain run on every rendered block
+        pub fn add_effect(&mut self, effect: Box<dyn Effect>) -> Result<(), NoiseError> {
+            if self.effect_count == MAX_EFFECTS {
+                return Err(NoiseError::LimitReached(format!("effects, {} at most", MAX_EFFECTS)));
+            }
+            self.send_change(Change::AddEffect(effect))?;
+            self.effect_count += 1;
+            return Ok(());
+        }
+
+        pub fn clear_effects(&mut self) -> Result<(), NoiseError> {
+            self.send_change(Change::Effects(Vec::with_capacity(MAX_EFFECTS)))?;
+            self.effect_count = 0;
+            return Ok(());
         }
 
         pub fn clip(&self, sample: f64, max: f64) -> f64 {
-            //println!("clip sample = {}, max = {}", sample, max);
-            return if sample >= 0.0 {
-                min_clip(sample, max)
-            } else {
-                max_clip(sample, -max)
-            };
+            return clip(sample, max);
         }
     }
 
+    fn clip(sample: f64, max: f64) -> f64 {
+        //println!("clip sample = {}, max = {}", sample, max);
+        return if sample >= 0.0 {
+            min_clip(sample, max)
+        } else {
+            max_clip(sample, max)
+        };
+    }
+
     fn min_clip(sample: f64, max: f64) -> f64 {
         return if sample < max { sample } else { max };
     }
@@ -450,8 +841,4 @@ pub mod noise {
     fn max_clip(sample: f64, max: f64) -> f64 {
         return if sample > -max { sample } else { -max };
     }
-
-    fn hz_to_angular(hertz: f64) -> f64 {
-       return hertz * 2.0 * PI;
-    }
 }
@@ -0,0 +1,179 @@
+pub mod noise_generator {
+
+    use crate::source::source::SoundSource;
+
+    // Rows summed by pink noise, each one octave lower than the previous
+    const PINK_ROWS: usize = 16;
+
+    // Brown noise integrator, step chosen for an RMS of about 0.25
+    const BROWN_LEAK: f64 = 0.9993;
+    const BROWN_STEP: f64 = 0.016;
+
+    // Small seeded generator (splitmix64) for everything random in the synth. The same seed gives
+    // the same sequence on every run and platform, which keeps offline renders reproducible.
+    #[derive(Clone, Debug)]
+    pub struct Random {
+        state: u64,
+    }
+
+    impl Random {
+        pub fn new(seed: u64) -> Self {
+            return Self { state: seed };
+        }
+
+        pub fn next_u64(&mut self) -> u64 {
+            self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = self.state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            return z ^ (z >> 31);
+        }
+
+        // Uniform in 0.0 .. 1.0
+        pub fn next_f64(&mut self) -> f64 {
+            return (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        }
+
+        // Uniform in -1.0 .. 1.0
+        pub fn next_bipolar(&mut self) -> f64 {
+            return self.next_f64() * 2.0 - 1.0;
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum NoiseColor {
+        White, // Flat spectrum
+        Pink,  // -3dB per octave (Voss-McCartney), wind, rain, more natural hiss
+        Brown, // -6dB per octave (integrated white), rumble, surf
+    }
+
+    // Noise source with the same interface as the oscillators, minus the frequency
+    #[derive(Clone, Debug)]
+    pub struct NoiseGenerator {
+        pub color: NoiseColor,
+        pub amplitude: f64,
+        random: Random,
+        seed: u64,
+        counter: u32,              // Pink: picks the row to refresh
+        rows: [f64; PINK_ROWS],    // Pink: held random values
+        running_sum: f64,          // Pink: sum of `rows`
+        level: f64,                // Brown: integrator state
+    }
+
+    impl NoiseGenerator {
+        pub fn new(color: NoiseColor, amplitude: f64, seed: u64) -> Self {
+            return Self {
+                color: color,
+                amplitude: amplitude,
+                random: Random::new(seed),
+                seed: seed,
+                counter: 0,
+                rows: [0.0; PINK_ROWS],
+                running_sum: 0.0,
+                level: 0.0,
+            };
+        }
+
+        fn value(&mut self) -> f64 {
+            return match self.color {
+                NoiseColor::White => self.random.next_bipolar(),
+                NoiseColor::Pink => {
+                    // Row n is refreshed every 2^n samples: the one of the lowest set bit of the counter
+                    self.counter = self.counter.wrapping_add(1);
+                    let row = self.counter.trailing_zeros() as usize;
+                    if row < PINK_ROWS {
+                        let value = self.random.next_bipolar();
+                        self.running_sum += value - self.rows[row];
+                        self.rows[row] = value;
+                    }
+                    // Plus white for the top octave, scaled so the sum stays within -1.0 ..= 1.0
+                    (self.running_sum + self.random.next_bipolar()) * (1.0 / (PINK_ROWS + 1) as f64)
+                }
+                NoiseColor::Brown => {
+                    // Leaky so it cannot wander off, the leak corner is around 5Hz at 44.1kHz.
+                    // The odd step that would still leave -1.0 ..= 1.0 is taken the other way.
+                    let step = BROWN_STEP * self.random.next_bipolar();
+                    let mut level = BROWN_LEAK * self.level + step;
+                    if level.abs() > 1.0 {
+                        level -= 2.0 * step;
+                    }
+                    self.level = level;
+                    level
+                }
+            };
+        }
+
+        pub fn next_sample(&mut self) -> f64 {
+            return self.amplitude * self.value();
+        }
+
+        // Fills `out` (mono) with the next out.len() samples
+        pub fn render(&mut self, out: &mut [f32]) {
+            for sample in out.iter_mut() {
+                *sample = self.next_sample() as f32;
+            }
+        }
+
+        // Like render() but adds to what is in `out`
+        pub fn render_add(&mut self, out: &mut [f32]) {
+            for sample in out.iter_mut() {
+                *sample += self.next_sample() as f32;
+            }
+        }
+    }
+
+    // Plays while a note is held, whatever its pitch
+    impl SoundSource for NoiseGenerator {
+        fn generate(&mut self, out: &mut [f32], _frequency: f64, _sample_rate: u32) {
+            self.render(out);
+        }
+
+        fn restart(&mut self) {
+            *self = Self::new(self.color, self.amplitude, self.seed);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn white_and_pink_stay_in_range() {
+            for color in [NoiseColor::White, NoiseColor::Pink].iter() {
+                let mut noise = NoiseGenerator::new(*color, 1.0, 1);
+                let mut out = vec![0.0; 1 << 20];
+                noise.render(&mut out);
+                assert!(out.iter().all(|sample| sample.abs() <= 1.0), "{:?}", color);
+            }
+        }
+
+        #[test]
+        fn brown_stays_bounded_without_drifting() {
+            for seed in 0 .. 4 {
+                let mut noise = NoiseGenerator::new(NoiseColor::Brown, 1.0, seed);
+                let mut out = vec![0.0; 1 << 20];
+                noise.render(&mut out);
+                assert!(out.iter().all(|sample| sample.abs() <= 1.0), "seed {}", seed);
+
+                // Every quarter (6s at 44.1kHz) stays centred on 0
+                for quarter in out.chunks(1 << 18) {
+                    let mean = quarter.iter().map(|sample| *sample as f64).sum::<f64>() / quarter.len() as f64;
+                    assert!(mean.abs() < 0.1, "seed {} mean {}", seed, mean);
+                }
+            }
+        }
+
+        #[test]
+        fn same_seed_same_noise() {
+            for color in [NoiseColor::White, NoiseColor::Pink, NoiseColor::Brown].iter() {
+                let render = |seed: u64| {
+                    let mut out = vec![0.0; 4096];
+                    NoiseGenerator::new(*color, 1.0, seed).render(&mut out);
+                    out
+                };
+                assert_eq!(render(7), render(7), "{:?}", color);
+                assert!(render(7) != render(8), "{:?}", color);
+            }
+        }
+    }
+}
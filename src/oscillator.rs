@@ -0,0 +1,371 @@
+pub mod oscillator {
+
+    use crate::noise_generator::noise_generator::Random;
+    use crate::source::source::SoundSource;
+
+    use std::f64::consts::PI;
+
+    // Most harmonics summed by Waveform::SawAnalog, fewer for high notes (see additive.rs
+    // for an oscillator that renders whole blocks and any harmonic table)
+    const SAW_ANALOG_HARMONICS: u32 = 99;
+
+    // Time constant duty cycle changes glide with, short enough for fast PWM, long enough not to click
+    const DUTY_SMOOTHING_SECONDS: f64 = 0.005;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Waveform {
+        Sine,
+        Square,
+        Triangle,
+        SawAnalog,  // Sum of sines, warm / slow
+        SawDigital, // Straight ramp, harsh / fast
+        Noise,      // White, ignores the frequency
+        SampleAndHold, // New random level every cycle, a stepped random LFO
+
+        // Anti-aliased versions of the naive shapes above (PolyBLEP / PolyBLAMP),
+        // cheap enough to use everywhere and clean up to the top of the keyboard
+        SquareBandLimited,
+        SawBandLimited,
+        TriangleBandLimited,
+
+        // High for `duty` of the cycle, low for the rest. Square is the 0.5 duty special case.
+        Pulse,
+        PulseBandLimited,
+    }
+
+    // One generator with its own phase, so any number of them can run at independent
+    // (and changing) frequencies without clicks or drift
+    #[derive(Clone, Debug)]
+    pub struct Oscillator {
+        pub waveform: Waveform,
+        pub frequency: f64, // Hz
+        pub amplitude: f64,
+        pub duty: f64,      // Pulse width, 0.01 ..= 0.99. Changes are smoothed, so it can be set from an LFO or envelope.
+        phase: f64,         // Position in the cycle, 0.0 .. 1.0
+        smoothed_duty: f64, // Duty actually played, glides towards `duty`
+        smoothing: (u32, f64), // Sample rate and the smoothing coefficient computed for it
+        random: Random,     // For Noise and SampleAndHold
+        seed: u64,          // `random` started from
+        held: f64,          // Current SampleAndHold level
+    }
+
+    impl Oscillator {
+        pub fn new(waveform: Waveform, frequency: f64, amplitude: f64) -> Self {
+            return Self::with_seed(waveform, frequency, amplitude, 0);
+        }
+
+        // Oscillators with the same seed produce the same Noise / SampleAndHold sequence
+        pub fn with_seed(waveform: Waveform, frequency: f64, amplitude: f64, seed: u64) -> Self {
+            let mut random = Random::new(seed);
+            let held = random.next_bipolar();
+            return Self {
+                waveform: waveform,
+                frequency: frequency,
+                amplitude: amplitude,
+                duty: 0.5,
+                phase: 0.0,
+                smoothed_duty: 0.5,
+                smoothing: (0, 0.0),
+                random: random,
+                seed: seed,
+                held: held,
+            };
+        }
+
+        // Back to phase 0 and the first values of the seed, settings are kept
+        pub fn restart(&mut self) {
+            let duty = self.duty;
+            *self = Self::with_seed(self.waveform, self.frequency, self.amplitude, self.seed);
+            self.duty = duty;
+        }
+
+        pub fn phase(&self) -> f64 {
+            return self.phase;
+        }
+
+        // Restarts the cycle, e.g. on note on for a hard synced sound
+        pub fn reset(&mut self, phase: f64) {
+            self.phase = phase.rem_euclid(1.0);
+        }
+
+        // Value at `phase`, amplitude not applied. `dt` is the phase step per sample.
+        // Noise has no phase and draws the next random value.
+        fn value(&mut self, phase: f64, dt: f64) -> f64 {
+            let angle = 2.0 * PI * phase;
+            return match self.waveform {
+                Waveform::Sine => f64::sin(angle),
+                Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+                Waveform::Triangle => f64::asin(f64::sin(angle)) * (2.0 / PI),
+                Waveform::SawAnalog => {
+                    // Only harmonics below Nyquist, the rest would alias. The sines come from
+                    // sin((n + 1) x) = 2 cos(x) sin(n x) - sin((n - 1) x), one sin / cos per sample.
+                    let (sine, cosine) = f64::sin_cos(angle);
+                    let coefficient = 2.0 * cosine;
+                    let (mut previous, mut current) = (0.0, sine);
+                    let mut output = 0.0;
+                    let mut n = 1;
+                    while n <= SAW_ANALOG_HARMONICS && n as f64 * dt < 0.5 {
+                        output += current / n as f64;
+                        let next = coefficient * current - previous;
+                        previous = current;
+                        current = next;
+                        n += 1;
+                    }
+                    output * (2.0 / PI)
+                }
+                Waveform::SawDigital => 2.0 * phase - 1.0,
+                Waveform::Noise => self.random.next_bipolar(),
+                Waveform::SampleAndHold => self.held,
+                Waveform::SquareBandLimited => {
+                    let naive = if phase < 0.5 { 1.0 } else { -1.0 };
+                    naive + poly_blep(phase, dt) - poly_blep((phase + 0.5).fract(), dt)
+                }
+                Waveform::SawBandLimited => 2.0 * phase - 1.0 - poly_blep(phase, dt),
+                Waveform::Pulse => if phase < self.smoothed_duty { 1.0 } else { -1.0 },
+                Waveform::PulseBandLimited => {
+                    // Rising edge at 0, falling edge at the duty
+                    let duty = self.smoothed_duty;
+                    let naive = if phase < duty { 1.0 } else { -1.0 };
+                    naive + poly_blep(phase, dt) - poly_blep((phase + 1.0 - duty).fract(), dt)
+                }
+                Waveform::TriangleBandLimited => {
+                    // Same phase as Triangle: 0 at 0.0, peaks at 0.25 and 0.75 where the slope flips by 8
+                    let naive = if phase < 0.25 { 4.0 * phase } else if phase < 0.75 { 2.0 - 4.0 * phase } else { 4.0 * phase - 4.0 };
+                    naive - 4.0 * dt * poly_blamp((phase + 0.75).fract(), dt) + 4.0 * dt * poly_blamp((phase + 0.25).fract(), dt)
+                }
+            };
+        }
+
+        // Moves the played duty cycle one sample towards `duty`. The first sample plays `duty`
+        // as set, only later changes glide.
+        fn smooth_duty(&mut self, sample_rate: u32) {
+            let target = self.duty.clamp(0.01, 0.99);
+            if self.smoothing.0 != sample_rate {
+                if self.smoothing.0 == 0 {
+                    self.smoothed_duty = target;
+                }
+                self.smoothing = (sample_rate, 1.0 - f64::exp(-1.0 / (DUTY_SMOOTHING_SECONDS * sample_rate as f64)));
+            }
+            self.smoothed_duty += (target - self.smoothed_duty) * self.smoothing.1;
+        }
+
+        // Next sample at the current frequency, amplitude and duty. Changing them between
+        // calls modulates the oscillator sample by sample, e.g. duty from an LFO for PWM.
+        pub fn next_sample(&mut self, sample_rate: u32) -> f64 {
+            self.smooth_duty(sample_rate);
+            // Past Nyquist nothing band limited is left to do
+            let dt = f64::min(f64::abs(self.frequency) / sample_rate as f64, 0.5);
+            let output = self.amplitude * self.value(self.phase, dt);
+            let phase = self.phase + self.frequency / sample_rate as f64;
+            if phase >= 1.0 || phase < 0.0 {
+                // New cycle
+                self.held = self.random.next_bipolar();
+            }
+            self.phase = phase.rem_euclid(1.0);
+            return output;
+        }
+
+        // Fills `out` (mono) with the next out.len() samples
+        pub fn render(&mut self, out: &mut [f32], sample_rate: u32) {
+            for sample in out.iter_mut() {
+                *sample = self.next_sample(sample_rate) as f32;
+            }
+        }
+
+        // Like render() but adds to what is in `out`, for summing several oscillators
+        pub fn render_add(&mut self, out: &mut [f32], sample_rate: u32) {
+            for sample in out.iter_mut() {
+                *sample += self.next_sample(sample_rate) as f32;
+            }
+        }
+    }
+
+    // Plays the waveform at the note frequency
+    impl SoundSource for Oscillator {
+        fn generate(&mut self, out: &mut [f32], frequency: f64, sample_rate: u32) {
+            self.frequency = frequency;
+            self.render(out, sample_rate);
+        }
+
+        fn restart(&mut self) {
+            Oscillator::restart(self);
+        }
+    }
+
+    // Two sample polynomial residual of a step from -1 to +1 at phase 0, to be added to the naive
+    // waveform. `t` is the phase (0.0 .. 1.0), `dt` the phase step per sample.
+    fn poly_blep(t: f64, dt: f64) -> f64 {
+        if t < dt {
+            let x = t / dt;
+            return 2.0 * x - x * x - 1.0;
+        }
+        if t > 1.0 - dt {
+            let x = (t - 1.0) / dt;
+            return x * x + 2.0 * x + 1.0;
+        }
+        return 0.0;
+    }
+
+    // Integrated poly_blep: residual of a corner at phase 0, scaled by the slope change times dt / 2
+    fn poly_blamp(t: f64, dt: f64) -> f64 {
+        if t < dt {
+            let x = t / dt - 1.0;
+            return -x * x * x / 3.0;
+        }
+        if t > 1.0 - dt {
+            let x = (t - 1.0) / dt + 1.0;
+            return x * x * x / 3.0;
+        }
+        return 0.0;
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const SAMPLE_RATE: u32 = 44100;
+        // 0.1s gives 10Hz bins, so a test frequency that is a multiple of 10Hz puts every harmonic
+        // and every alias of it exactly on a bin
+        const LENGTH: usize = 4410;
+
+        // Power of `signal` at DFT bin `bin` (Goertzel)
+        fn bin_power(signal: &[f32], bin: usize) -> f64 {
+            let coefficient = 2.0 * f64::cos(2.0 * PI * bin as f64 / signal.len() as f64);
+            let (mut previous, mut before_previous) = (0.0, 0.0);
+            for sample in signal.iter() {
+                let current = *sample as f64 + coefficient * previous - before_previous;
+                before_previous = previous;
+                previous = current;
+            }
+            return previous * previous + before_previous * before_previous - coefficient * previous * before_previous;
+        }
+
+        fn render(waveform: Waveform, frequency: f64) -> Vec<f32> {
+            return render_pulse(waveform, frequency, 0.5);
+        }
+
+        fn render_pulse(waveform: Waveform, frequency: f64, duty: f64) -> Vec<f32> {
+            let mut oscillator = Oscillator::new(waveform, frequency, 1.0);
+            oscillator.duty = duty;
+            let mut signal = vec![0.0; LENGTH];
+            oscillator.render(&mut signal, SAMPLE_RATE);
+            return signal;
+        }
+
+        // Energy off the harmonic series of `frequency` relative to the energy on it, in dB
+        fn aliasing_db(signal: &[f32], frequency: f64) -> f64 {
+            let fundamental = (frequency * LENGTH as f64 / SAMPLE_RATE as f64).round() as usize;
+            let (mut harmonics, mut aliases) = (0.0, 0.0);
+            for bin in 1 .. LENGTH / 2 {
+                if bin % fundamental == 0 {
+                    harmonics += bin_power(signal, bin);
+                } else {
+                    aliases += bin_power(signal, bin);
+                }
+            }
+            return 10.0 * f64::log10(aliases / harmonics);
+        }
+
+        // Test notes between 3 and 4.5kHz, high enough for the naive shapes to alias badly
+        const FREQUENCIES: [f64; 3] = [2990.0, 3730.0, 4400.0];
+
+        fn assert_aliases_less(naive: Waveform, band_limited: Waveform, reduction_db: f64) {
+            for frequency in FREQUENCIES.iter() {
+                let naive_db = aliasing_db(&render(naive, *frequency), *frequency);
+                let band_limited_db = aliasing_db(&render(band_limited, *frequency), *frequency);
+                assert!(band_limited_db < naive_db - reduction_db,
+                    "{:?} at {}Hz: {:.1}dB of aliasing, {:?} has {:.1}dB", band_limited, frequency, band_limited_db, naive, naive_db);
+            }
+        }
+
+        #[test]
+        fn square_band_limited_aliases_less() {
+            assert_aliases_less(Waveform::Square, Waveform::SquareBandLimited, 12.0);
+        }
+
+        #[test]
+        fn saw_band_limited_aliases_less() {
+            assert_aliases_less(Waveform::SawDigital, Waveform::SawBandLimited, 10.0);
+        }
+
+        // A wrongly scaled PolyBLAMP (2x, half, inverted) only gains 3 to 5dB or makes it worse
+        #[test]
+        fn triangle_band_limited_aliases_less() {
+            assert_aliases_less(Waveform::Triangle, Waveform::TriangleBandLimited, 10.0);
+        }
+
+        // Not much narrower though: at 10% a 4kHz pulse is barely a sample wide, and the two
+        // corrections overlap
+        #[test]
+        fn pulse_band_limited_aliases_less() {
+            for frequency in FREQUENCIES.iter() {
+                for duty in [0.25, 0.4, 0.7].iter() {
+                    let naive_db = aliasing_db(&render_pulse(Waveform::Pulse, *frequency, *duty), *frequency);
+                    let band_limited_db = aliasing_db(&render_pulse(Waveform::PulseBandLimited, *frequency, *duty), *frequency);
+                    assert!(band_limited_db < naive_db - 10.0,
+                        "duty {} at {}Hz: {:.1}dB of aliasing, naive has {:.1}dB", duty, frequency, band_limited_db, naive_db);
+                }
+            }
+        }
+
+        // High for `duty` of the cycle and low for the rest averages to 2 * duty - 1
+        #[test]
+        fn pulse_mean_follows_the_duty() {
+            // 44 whole cycles of 100 samples, so even the naive edges fall on samples
+            for waveform in [Waveform::Pulse, Waveform::PulseBandLimited].iter() {
+                for duty in [0.1, 0.25, 0.5, 0.8].iter() {
+                    let signal = &render_pulse(*waveform, 441.0, *duty)[.. 4400];
+                    let mean = signal.iter().map(|sample| *sample as f64).sum::<f64>() / signal.len() as f64;
+                    assert!((mean - (2.0 * duty - 1.0)).abs() < 0.01, "{:?} with duty {} averages {}", waveform, duty, mean);
+                }
+            }
+        }
+
+        #[test]
+        fn duty_starts_as_set_then_glides() {
+            let mut oscillator = Oscillator::new(Waveform::PulseBandLimited, 440.0, 1.0);
+            oscillator.duty = 0.2;
+            oscillator.next_sample(SAMPLE_RATE);
+            assert_eq!(oscillator.smoothed_duty, 0.2);
+
+            // One time constant closes all but 1/e of the gap, ten leave nothing audible
+            oscillator.duty = 0.6;
+            let time_constant = (DUTY_SMOOTHING_SECONDS * SAMPLE_RATE as f64).round() as usize;
+            for _ in 0 .. time_constant {
+                oscillator.next_sample(SAMPLE_RATE);
+            }
+            let expected = 0.6 - 0.4 / std::f64::consts::E;
+            assert!((oscillator.smoothed_duty - expected).abs() < 0.005, "{} after one time constant", oscillator.smoothed_duty);
+            for _ in 0 .. 9 * time_constant {
+                oscillator.next_sample(SAMPLE_RATE);
+            }
+            assert!((oscillator.smoothed_duty - 0.6).abs() < 1e-3);
+        }
+
+        // Same series, summed per sample here and per block there
+        #[test]
+        fn saw_analog_matches_additive_saw() {
+            use crate::additive::additive::AdditiveOscillator;
+            for frequency in [55.0, 440.0, 3730.0].iter() {
+                let mut additive = AdditiveOscillator::saw(SAW_ANALOG_HARMONICS as usize, *frequency, 1.0);
+                let mut expected = vec![0.0; LENGTH];
+                additive.render(&mut expected, SAMPLE_RATE);
+                let error = render(Waveform::SawAnalog, *frequency).iter().zip(expected.iter()).map(|(x, y)| (x - y).abs()).fold(0.0, f32::max);
+                assert!(error < 1e-4, "{}Hz is off by {}", frequency, error);
+            }
+        }
+
+        // The corrections only touch the samples around the edges, the fundamental stays put
+        #[test]
+        fn band_limited_keeps_the_fundamental() {
+            let fundamental = (3730.0 * LENGTH as f64 / SAMPLE_RATE as f64) as usize;
+            let shapes = [(Waveform::Square, Waveform::SquareBandLimited), (Waveform::SawDigital, Waveform::SawBandLimited), (Waveform::Triangle, Waveform::TriangleBandLimited)];
+            for (naive, band_limited) in shapes.iter() {
+                let naive_power = bin_power(&render(*naive, 3730.0), fundamental);
+                let band_limited_power = bin_power(&render(*band_limited, 3730.0), fundamental);
+                let difference_db = 10.0 * f64::log10(band_limited_power / naive_power);
+                assert!(difference_db.abs() < 1.0, "{:?} fundamental is {:.2}dB off {:?}", band_limited, difference_db, naive);
+            }
+        }
+    }
+}
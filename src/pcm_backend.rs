@@ -0,0 +1,84 @@
+pub mod pcm_backend {
+
+    use crate::backend::backend::{AudioBackend, BlockSignal, DeviceInfo, SampleFormat, StreamFormat};
+    use crate::error::error::NoiseError;
+
+    use std::fs::OpenOptions;
+    use std::io::{self, Write};
+    use std::sync::Arc;
+
+    pub const STDOUT_TARGET: &str = "-";
+
+    // Headerless interleaved PCM written to stdout or a file / named pipe, e.g.
+    //   synthesizer --backend pcm --format s16le | aplay -f S16_LE -r 44100 -c 1
+    // Writes block when the reader is slow, which paces the block loop like a sound card would.
+    pub struct PcmBackend {
+        target: String,
+        writer: Option<Box<dyn Write + Send>>,
+        signal: Option<Arc<BlockSignal>>,
+    }
+
+    impl PcmBackend {
+        // `target` is a path (regular file or fifo) or "-" for stdout
+        pub fn new(target: &str) -> Self {
+            return Self {
+                target: target.to_string(),
+                writer: None,
+                signal: None,
+            };
+        }
+    }
+
+    impl AudioBackend for PcmBackend {
+        fn enumerate(&self) -> Vec<DeviceInfo> {
+            return vec![DeviceInfo::any(0, &self.target, "pcm", vec![SampleFormat::I16, SampleFormat::I24, SampleFormat::I32, SampleFormat::F32])];
+        }
+
+        fn open(&mut self, _: usize, format: StreamFormat, signal: Arc<BlockSignal>) -> Result<(), NoiseError> {
+            let writer: Box<dyn Write + Send> = if self.target == STDOUT_TARGET {
+                Box::new(io::stdout())
+            } else {
+                // Fifos ignore create/truncate, plain files get (re)created
+                match OpenOptions::new().write(true).create(true).truncate(true).open(&self.target) {
+                    Ok(file) => Box::new(file),
+                    Err(e) => return Err(NoiseError::BackendError(format!("failed to open {}: {}", self.target, e))),
+                }
+            };
+            eprintln!("Streaming {} PCM to {}", format.sample_format.name(), self.target);
+
+            self.writer = Some(writer);
+            self.signal = Some(signal);
+            return Ok(());
+        }
+
+        fn submit(&mut self, _: usize, samples: &[u8]) {
+            if let Some(writer) = self.writer.as_mut() {
+                if let Err(e) = writer.write_all(samples).and_then(|_| writer.flush()) {
+                    // Usually the reader went away (broken pipe), nothing more can be written
+                    eprintln!("PCM write to {} failed: {}", self.target, e);
+                    self.writer = None;
+                }
+            }
+
+            // Without a writer the block loop would spin rendering into nothing, end it instead
+            if let Some(signal) = self.signal.as_ref() {
+                if self.writer.is_some() {
+                    signal.block_done();
+                } else {
+                    signal.fail();
+                }
+            }
+        }
+
+        fn close(&mut self) {
+            if let Some(mut writer) = self.writer.take() {
+                let _ = writer.flush();
+            }
+            self.signal = None;
+        }
+    }
+
+    impl Drop for PcmBackend {
+        fn drop(&mut self) { self.close(); }
+    }
+}
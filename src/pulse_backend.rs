@@ -0,0 +1,133 @@
+pub mod pulse_backend {
+
+    extern crate libpulse_binding as pulse;
+    extern crate libpulse_simple_binding as psimple;
+
+    use crate::backend::backend::{AudioBackend, BlockSignal, DeviceInfo, SampleFormat, StreamFormat};
+    use crate::error::error::NoiseError;
+
+    use psimple::Simple;
+    use pulse::def::BufferAttr;
+    use pulse::sample::{Format, Spec};
+    use pulse::stream::Direction;
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // Playback through a PulseAudio compatible server (PulseAudio itself or pipewire-pulse)
+    // using the simple API. Only the server's default sink is offered.
+    pub struct PulseBackend {
+        app_name: String,
+        stream: Option<Simple>,
+        signal: Option<Arc<BlockSignal>>,
+        buffer_latency: Option<Duration>, // What tlength holds, the latency once the buffer is full
+    }
+
+    // The stream is only used by the thread that owns the backend
+    unsafe impl Send for PulseBackend {}
+
+    impl PulseBackend {
+        pub fn new(app_name: &str) -> Self {
+            return Self {
+                app_name: app_name.to_string(),
+                stream: None,
+                signal: None,
+                buffer_latency: None,
+            };
+        }
+
+        // Maps NoiseMaker's block layout onto the server side buffer: the server keeps
+        // `blocks` blocks queued and asks for more one block at a time.
+        fn buffer_attr(format: &StreamFormat) -> BufferAttr {
+            let block_bytes = format.block_samples * format.sample_format.bytes() as u32;
+            return BufferAttr {
+                maxlength: block_bytes * format.blocks,
+                tlength: block_bytes * format.blocks,
+                prebuf: block_bytes,
+                minreq: block_bytes,
+                fragsize: u32::MAX, // Playback only
+            };
+        }
+    }
+
+    impl AudioBackend for PulseBackend {
+        fn enumerate(&self) -> Vec<DeviceInfo> {
+            // The server resamples and converts, so any layout up to PA_CHANNELS_MAX goes
+            let mut device = DeviceInfo::any(0, "default", "pulse", vec![SampleFormat::I16, SampleFormat::I24, SampleFormat::I32, SampleFormat::F32]);
+            device.channels = (1..=32).collect();
+            return vec![device];
+        }
+
+        fn open(&mut self, _: usize, format: StreamFormat, signal: Arc<BlockSignal>) -> Result<(), NoiseError> {
+            let spec = Spec {
+                format: match format.sample_format {
+                    SampleFormat::I16 => Format::S16le,
+                    SampleFormat::I24 => Format::S24le,
+                    SampleFormat::I32 => Format::S32le,
+                    SampleFormat::F32 => Format::F32le,
+                },
+                channels: format.channels as u8,
+                rate: format.sample_rate,
+            };
+            if !spec.is_valid() {
+                return Err(NoiseError::FormatUnsupported(format!("invalid PulseAudio sample spec {:?}", spec)));
+            }
+
+            let attr = Self::buffer_attr(&format);
+            eprintln!("Opening PulseAudio stream (tlength = {} | minreq = {})", attr.tlength, attr.minreq);
+            match Simple::new(None, &self.app_name, Direction::Playback, None, "Synthesizer", &spec, None, Some(&attr)) {
+                Ok(stream) => {
+                    self.stream = Some(stream);
+                    self.signal = Some(signal);
+                    let frame_bytes = format.channels as u64 * format.sample_format.bytes() as u64;
+                    self.buffer_latency = Some(Duration::from_micros(attr.tlength as u64 * 1_000_000 / (frame_bytes * format.sample_rate as u64)));
+                    eprintln!("PulseAudio latency = {:?}", self.latency());
+                    return Ok(());
+                }
+                Err(e) => return Err(NoiseError::OpenFailed(e.0)),
+            }
+        }
+
+        fn submit(&mut self, _: usize, samples: &[u8]) {
+            if let Some(stream) = self.stream.as_ref() {
+                // Blocks until the server has room for the block
+                if let Err(e) = stream.write(samples) {
+                    // Usually the server went away, the stream cannot be written any more
+                    eprintln!("PulseAudio write failed: {:?}", e);
+                    self.stream = None;
+                }
+            }
+
+            // Without a stream the block loop would spin rendering into nothing, end it instead
+            if let Some(signal) = self.signal.as_ref() {
+                if self.stream.is_some() {
+                    signal.block_done();
+                } else {
+                    signal.fail();
+                }
+            }
+        }
+
+        fn close(&mut self) {
+            if let Some(stream) = self.stream.take() {
+                let _ = stream.drain();
+            }
+            self.signal = None;
+            self.buffer_latency = None;
+        }
+
+        // Measured by the server, which only knows once blocks were written. Before that
+        // (right after open) the full buffer is what the first block will wait behind.
+        fn latency(&self) -> Option<Duration> {
+            let stream = self.stream.as_ref()?;
+            return match stream.get_latency() {
+                Ok(latency) if latency.0 > 0 => Some(Duration::from_micros(latency.0)),
+                _ => self.buffer_latency,
+            };
+        }
+    }
+
+    impl Drop for PulseBackend {
+        fn drop(&mut self) { self.close(); }
+    }
+}
@@ -0,0 +1,96 @@
+pub mod source {
+
+    // Where in the stream a sound source is being asked for samples
+    #[derive(Clone, Copy, Debug)]
+    pub struct ProcessContext {
+        pub time: f64,        // Engine time in seconds (of the first frame for SoundSource::process)
+        pub frame: u64,       // Same as `time`, in frames since start. Exact, prefer it for long running state.
+        pub channel: u32,     // Channel being produced, 0 .. channels (user functions only)
+        pub channels: u32,
+        pub sample_rate: u32,
+        pub frequency: f64,   // Current Param::Frequency
+        pub output: f64,      // Set by the user function, -1.0 ..= 1.0
+    }
+
+    // Replaces the default sound. Called once per channel of every frame on the audio thread,
+    // so it must not block; captured state lives as long as the NoiseMaker (restarts keep it).
+    pub type UserFunction = Box<dyn FnMut(&mut ProcessContext) + Send>;
+
+    // Anything NoiseMaker can play: instruments, test tones, sample players...
+    // All methods run on the audio thread.
+    pub trait SoundSource: Send {
+        // Fills `out` with interleaved frames of ctx.channels samples each, nominally -1.0 ..= 1.0.
+        // Blocks are split at command boundaries, so parameters are constant within one call.
+        // By default: silence while no note is held (frequency 0), generate() otherwise.
+        fn process(&mut self, out: &mut [f32], ctx: &ProcessContext) {
+            if ctx.frequency == 0.0 {
+                out.iter_mut().for_each(|sample| *sample = 0.0);
+                return;
+            }
+            self.generate(out, ctx.frequency, ctx.sample_rate);
+        }
+
+        // For sources that only need a pitch (oscillators, noise) instead of overriding process():
+        // fills `out` (mono) with the next out.len() samples at `frequency`
+        fn generate(&mut self, out: &mut [f32], _frequency: f64, _sample_rate: u32) {
+            out.iter_mut().for_each(|sample| *sample = 0.0);
+        }
+
+        // Channels the source renders, None for whatever the output has. Mono sources
+        // are panned into stereo outputs, other layouts are mapped channel for channel.
+        fn channels(&self) -> Option<u32> {
+            return Some(1);
+        }
+
+        // Called at the exact sample a NoteOn / NoteOff command takes effect
+        fn note_on(&mut self, _frequency: f64, _time: f64) {}
+
+        fn note_off(&mut self, _time: f64) {}
+
+        // Back to how the source was created (phase, envelope, random sequence). Called whenever
+        // playback starts over from time 0, so every start() / render() sounds the same.
+        fn restart(&mut self) {}
+    }
+
+    // Block processor run on the mixed output before it is converted for the device
+    // (filters, delays, limiters...). Runs on the audio thread.
+    pub trait Effect: Send {
+        // `block` holds interleaved frames of ctx.channels samples, ctx.time is the time of the first one
+        fn process(&mut self, block: &mut [f32], ctx: &ProcessContext);
+
+        // Clears delay lines, filter state... as SoundSource::restart does for sources
+        fn restart(&mut self) {}
+    }
+
+    // Per-sample UserFunction as a SoundSource
+    pub struct FunctionSource {
+        function: UserFunction,
+    }
+
+    impl FunctionSource {
+        pub fn new(function: UserFunction) -> Self {
+            return Self { function: function };
+        }
+    }
+
+    impl SoundSource for FunctionSource {
+        // The function is asked for every output channel itself
+        fn channels(&self) -> Option<u32> {
+            return None;
+        }
+
+        fn process(&mut self, out: &mut [f32], ctx: &ProcessContext) {
+            let mut sample_ctx = *ctx;
+            for (n, frame) in out.chunks_mut(ctx.channels as usize).enumerate() {
+                sample_ctx.frame = ctx.frame + n as u64;
+                sample_ctx.time = sample_ctx.frame as f64 / ctx.sample_rate as f64;
+                for (channel, sample) in frame.iter_mut().enumerate() {
+                    sample_ctx.channel = channel as u32;
+                    sample_ctx.output = 0.0;
+                    (self.function)(&mut sample_ctx);
+                    *sample = sample_ctx.output as f32;
+                }
+            }
+        }
+    }
+}
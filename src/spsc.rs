@@ -0,0 +1,100 @@
+pub mod spsc {
+
+    use std::cell::UnsafeCell;
+    use std::mem::MaybeUninit;
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Fixed size single producer / single consumer ring buffer. Neither side ever locks or
+    // allocates, so the consumer can live on the audio thread.
+    struct Ring<T> {
+        slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+        head: AtomicUsize, // Next slot to read, only written by the consumer
+        tail: AtomicUsize, // Next slot to write, only written by the producer
+    }
+
+    // A slot is only ever accessed by one side at a time, head / tail hand them over
+    unsafe impl<T: Send> Send for Ring<T> {}
+    unsafe impl<T: Send> Sync for Ring<T> {}
+
+    pub struct Producer<T> {
+        ring: Arc<Ring<T>>,
+    }
+
+    pub struct Consumer<T> {
+        ring: Arc<Ring<T>>,
+    }
+
+    // Queue holding up to `capacity` values
+    pub fn channel<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+        // One slot always stays empty to tell a full ring from an empty one
+        let slots: Vec<UnsafeCell<MaybeUninit<T>>> = (0 .. capacity + 1).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect();
+        let ring = Arc::new(Ring {
+            slots: slots.into_boxed_slice(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        });
+        return (Producer { ring: Arc::clone(&ring) }, Consumer { ring: ring });
+    }
+
+    impl<T> Ring<T> {
+        fn next(&self, index: usize) -> usize {
+            return (index + 1) % self.slots.len();
+        }
+    }
+
+    impl<T> Producer<T> {
+        // Gives the value back if the queue is full
+        pub fn push(&mut self, value: T) -> Result<(), T> {
+            let ring = &*self.ring;
+            let tail = ring.tail.load(Ordering::Relaxed);
+            let next = ring.next(tail);
+            if next == ring.head.load(Ordering::Acquire) {
+                return Err(value);
+            }
+
+            unsafe {
+                ptr::write((*ring.slots[tail].get()).as_mut_ptr(), value);
+            }
+            ring.tail.store(next, Ordering::Release);
+            return Ok(());
+        }
+
+        pub fn capacity(&self) -> usize {
+            return self.ring.slots.len() - 1;
+        }
+    }
+
+    impl<T> Consumer<T> {
+        pub fn pop(&mut self) -> Option<T> {
+            let ring = &*self.ring;
+            let head = ring.head.load(Ordering::Relaxed);
+            if head == ring.tail.load(Ordering::Acquire) {
+                return None;
+            }
+
+            let value = unsafe { ptr::read((*ring.slots[head].get()).as_ptr()) };
+            ring.head.store(ring.next(head), Ordering::Release);
+            return Some(value);
+        }
+
+        pub fn capacity(&self) -> usize {
+            return self.ring.slots.len() - 1;
+        }
+    }
+
+    impl<T> Drop for Ring<T> {
+        // Drops whatever was pushed but never popped
+        fn drop(&mut self) {
+            let tail = *self.tail.get_mut();
+            let mut head = *self.head.get_mut();
+            while head != tail {
+                unsafe {
+                    ptr::drop_in_place((*self.slots[head].get()).as_mut_ptr());
+                }
+                head = self.next(head);
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+pub mod voice {
+
+    use crate::source::source::{ProcessContext, SoundSource};
+
+    use std::f32::consts::FRAC_PI_4;
+
+    // A sound source placed in the output: renders in its own channel layout and is
+    // panned / up- or downmixed into the engine's interleaved block.
+    pub struct Voice {
+        source: Box<dyn SoundSource>,
+        pan: f64,         // -1.0 (left) ..= 1.0 (right)
+        buffer: Vec<f32>, // Source output before it is mixed in
+    }
+
+    impl Voice {
+        pub fn new(source: Box<dyn SoundSource>, pan: f64) -> Self {
+            return Self {
+                source: source,
+                pan: pan.clamp(-1.0, 1.0),
+                buffer: Vec::new(),
+            };
+        }
+
+        pub fn set_pan(&mut self, pan: f64) {
+            self.pan = pan.clamp(-1.0, 1.0);
+        }
+
+        pub fn source(&mut self) -> &mut dyn SoundSource {
+            return &mut *self.source;
+        }
+
+        // Renders out.len() / ctx.channels frames and adds them to `out`
+        pub fn render(&mut self, out: &mut [f32], ctx: &ProcessContext) {
+            let channels = ctx.channels as usize;
+            let frames = out.len() / channels;
+            let source_channels = self.source.channels().unwrap_or(ctx.channels).max(1) as usize;
+
+            let mut source_ctx = *ctx;
+            source_ctx.channels = source_channels as u32;
+            self.buffer.resize(frames * source_channels, 0.0);
+            self.source.process(&mut self.buffer, &source_ctx);
+
+            let input = self.buffer.chunks(source_channels);
+            if source_channels == 1 && channels >= 2 {
+                // Mono to stereo, equal power so a centred voice keeps its loudness
+                let angle = (self.pan as f32 + 1.0) * FRAC_PI_4;
+                let (left, right) = (angle.cos(), angle.sin());
+                for (frame, x) in out.chunks_mut(channels).zip(input) {
+                    frame[0] += x[0] * left;
+                    frame[1] += x[0] * right;
+                }
+            } else if source_channels >= 2 && channels == 1 {
+                // Downmix to mono
+                let scale = 1.0 / source_channels as f32;
+                for (frame, x) in out.iter_mut().zip(input) {
+                    *frame += x.iter().sum::<f32>() * scale;
+                }
+            } else {
+                // Channel for channel, pan acts as balance on the first two
+                let left = (1.0 - self.pan as f32).min(1.0);
+                let right = (1.0 + self.pan as f32).min(1.0);
+                for (frame, x) in out.chunks_mut(channels).zip(input) {
+                    for (channel, (sample, y)) in frame.iter_mut().zip(x.iter()).enumerate() {
+                        let gain = match (channel, channels) {
+                            (_, 1) => 1.0,
+                            (0, _) => left,
+                            (1, _) => right,
+                            _ => 1.0,
+                        };
+                        *sample += y * gain;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,189 @@
+pub mod wav_backend {
+
+    use crate::backend::backend::{AudioBackend, BlockSignal, DeviceInfo, SampleFormat, StreamFormat};
+    use crate::error::error::NoiseError;
+
+    use std::fs::File;
+    use std::io::{BufWriter, Seek, SeekFrom, Write};
+    use std::sync::Arc;
+
+    const WAVE_FORMAT_PCM: u16 = 0x0001;
+    const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+    const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+    // Tail shared by the KSDATAFORMAT_SUBTYPE_* GUIDs, the first 4 bytes are the format tag
+    const SUBTYPE_GUID_TAIL: [u8; 12] = [0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71];
+
+    // Writes every submitted block to a RIFF/WAVE file instead of a sound card.
+    // Blocks are released as soon as they are written, so the block loop runs as fast as the disk allows.
+    pub struct WavBackend {
+        path: String,
+        writer: Option<BufWriter<File>>,
+        format: Option<StreamFormat>,
+        data_len: u32,
+        signal: Option<Arc<BlockSignal>>,
+    }
+
+    impl WavBackend {
+        pub fn new(path: &str) -> Self {
+            return Self {
+                path: path.to_string(),
+                writer: None,
+                format: None,
+                data_len: 0,
+                signal: None,
+            };
+        }
+
+        // Header bytes in front of the samples
+        fn header_len(format: &StreamFormat) -> u32 {
+            let fmt_len: u32 = if Self::is_extensible(format) { 40 } else { 16 };
+            let fact_len: u32 = if format.sample_format.is_float() { 12 } else { 0 };
+            return 12 + 8 + fmt_len + fact_len + 8;
+        }
+
+        // Most sample bytes that still fit the 32 bit RIFF size field
+        fn max_data_len(format: &StreamFormat) -> u32 {
+            let block_align = (format.sample_format.bytes() * format.channels as usize) as u32;
+            let max = u32::MAX - (Self::header_len(format) - 8);
+            return max - max % block_align;
+        }
+
+        // More than 16 bits or 2 channels need WAVEFORMATEXTENSIBLE to be read reliably
+        fn is_extensible(format: &StreamFormat) -> bool {
+            return format.sample_format.bits() > 16 || format.channels > 2;
+        }
+
+        fn write_header(writer: &mut BufWriter<File>, format: &StreamFormat, data_len: u32) -> std::io::Result<()> {
+            let sample_format = format.sample_format;
+            let bits_per_sample = sample_format.bits();
+            let block_align = sample_format.bytes() as u16 * format.channels as u16;
+            let byte_rate = format.sample_rate * block_align as u32;
+            let format_tag = if sample_format.is_float() { WAVE_FORMAT_IEEE_FLOAT } else { WAVE_FORMAT_PCM };
+            let extensible = Self::is_extensible(format);
+
+            // Non PCM data needs a fact chunk with the frame count
+            let fmt_len: u32 = if extensible { 40 } else { 16 };
+            let fact_len: u32 = if sample_format.is_float() { 12 } else { 0 };
+            let header_len = Self::header_len(format);
+
+            writer.write_all(b"RIFF")?;
+            writer.write_all(&(header_len - 8 + data_len).to_le_bytes())?;
+            writer.write_all(b"WAVE")?;
+
+            writer.write_all(b"fmt ")?;
+            writer.write_all(&fmt_len.to_le_bytes())?;
+            writer.write_all(&(if extensible { WAVE_FORMAT_EXTENSIBLE } else { format_tag }).to_le_bytes())?;
+            writer.write_all(&(format.channels as u16).to_le_bytes())?;
+            writer.write_all(&format.sample_rate.to_le_bytes())?;
+            writer.write_all(&byte_rate.to_le_bytes())?;
+            writer.write_all(&block_align.to_le_bytes())?;
+            writer.write_all(&bits_per_sample.to_le_bytes())?;
+            if extensible {
+                // The first `channels` speaker positions (front left, front right, centre...)
+                let channel_mask: u32 = if format.channels < 32 { (1 << format.channels) - 1 } else { 0 };
+                writer.write_all(&22u16.to_le_bytes())?; // cbSize
+                writer.write_all(&bits_per_sample.to_le_bytes())?; // wValidBitsPerSample
+                writer.write_all(&channel_mask.to_le_bytes())?;
+                writer.write_all(&(format_tag as u32).to_le_bytes())?; // SubFormat
+                writer.write_all(&SUBTYPE_GUID_TAIL)?;
+            }
+
+            if fact_len > 0 {
+                writer.write_all(b"fact")?;
+                writer.write_all(&4u32.to_le_bytes())?;
+                writer.write_all(&(data_len / block_align as u32).to_le_bytes())?;
+            }
+
+            writer.write_all(b"data")?;
+            writer.write_all(&data_len.to_le_bytes())?;
+            return Ok(());
+        }
+
+        // Rewrites the header now that the final length is known
+        fn finish(&mut self) -> std::io::Result<()> {
+            if let (Some(writer), Some(format)) = (self.writer.as_mut(), self.format.as_ref()) {
+                writer.seek(SeekFrom::Start(0))?;
+                Self::write_header(writer, format, self.data_len)?;
+                writer.flush()?;
+            }
+            return Ok(());
+        }
+
+        // Closes the file with what was written so far and stops the block loop
+        fn fail(&mut self) {
+            if let Err(e) = self.finish() {
+                eprintln!("Failed to finish wav file {}: {}", self.path, e);
+            }
+            self.writer = None;
+            if let Some(signal) = self.signal.as_ref() {
+                signal.fail();
+            }
+        }
+    }
+
+    impl AudioBackend for WavBackend {
+        fn enumerate(&self) -> Vec<DeviceInfo> {
+            return vec![DeviceInfo::any(0, &self.path, "wav", vec![SampleFormat::I16, SampleFormat::I24, SampleFormat::I32, SampleFormat::F32])];
+        }
+
+        fn open(&mut self, _: usize, format: StreamFormat, signal: Arc<BlockSignal>) -> Result<(), NoiseError> {
+            eprintln!("Opening wav file {}", self.path);
+            let file = match File::create(&self.path) {
+                Ok(file) => file,
+                Err(e) => return Err(NoiseError::BackendError(format!("failed to create {}: {}", self.path, e))),
+            };
+
+            let mut writer = BufWriter::new(file);
+            if let Err(e) = Self::write_header(&mut writer, &format, 0) {
+                return Err(NoiseError::BackendError(format!("failed to write wav header: {}", e)));
+            }
+
+            self.writer = Some(writer);
+            self.format = Some(format);
+            self.data_len = 0;
+            self.signal = Some(signal);
+            return Ok(());
+        }
+
+        fn submit(&mut self, _: usize, samples: &[u8]) {
+            let (writer, format) = match (self.writer.as_mut(), self.format.as_ref()) {
+                (Some(writer), Some(format)) => (writer, format),
+                _ => return,
+            };
+
+            // RIFF sizes are 32 bit, a block that does not fit ends the file
+            let data_len = self.data_len.checked_add(samples.len() as u32).filter(|len| *len <= Self::max_data_len(format));
+            let data_len = match data_len {
+                Some(data_len) => data_len,
+                None => {
+                    eprintln!("{} reached the 4GiB wav size limit, not writing any more", self.path);
+                    return self.fail();
+                }
+            };
+            if let Err(e) = writer.write_all(samples) {
+                eprintln!("Failed to write wav data: {}", e);
+                return self.fail();
+            }
+            self.data_len = data_len;
+
+            // The block is on disk, hand it straight back
+            if let Some(signal) = self.signal.as_ref() {
+                signal.block_done();
+            }
+        }
+
+        fn close(&mut self) {
+            if let Err(e) = self.finish() {
+                eprintln!("Failed to finish wav file {}: {}", self.path, e);
+            }
+            self.writer = None;
+            self.format = None;
+            self.signal = None;
+        }
+    }
+
+    impl Drop for WavBackend {
+        fn drop(&mut self) { self.close(); }
+    }
+}
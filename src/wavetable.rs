@@ -0,0 +1,489 @@
+pub mod wavetable {
+
+    use crate::error::error::NoiseError;
+    use crate::source::source::SoundSource;
+
+    use std::f64::consts::PI;
+    use std::fs;
+    use std::sync::Arc;
+
+    // Samples per cycle tables are resampled to when they are not a power of two
+    pub const DEFAULT_TABLE_SIZE: usize = 2048;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Interpolation {
+        Linear,
+        Cubic, // Catmull-Rom over 4 points, cleaner highs for a few more multiplies
+    }
+
+    // Single-cycle frames, each band limited once per octave. Level k keeps the harmonics up to
+    // size / 2 >> k, so playing the right level never puts anything above Nyquist.
+    pub struct Wavetable {
+        size: usize,              // Samples per cycle, a power of two
+        frame_count: usize,
+        levels: Vec<Vec<Vec<f32>>>, // [level][frame][sample]
+    }
+
+    impl Wavetable {
+        // Frames of equal length, any length of 4 samples or more
+        pub fn from_frames(frames: Vec<Vec<f32>>) -> Result<Self, NoiseError> {
+            let length = match frames.first() {
+                Some(frame) => frame.len(),
+                None => return Err(NoiseError::LoadFailed("wavetable without frames".to_string())),
+            };
+            if length < 4 || frames.iter().any(|frame| frame.len() != length) {
+                return Err(NoiseError::LoadFailed("wavetable frames must have the same length of at least 4 samples".to_string()));
+            }
+
+            let size = if length.is_power_of_two() { length } else { DEFAULT_TABLE_SIZE };
+            let level_count = size.trailing_zeros() as usize - 1; // Down to a single harmonic
+            let mut levels: Vec<Vec<Vec<f32>>> = vec![Vec::with_capacity(frames.len()); level_count];
+
+            for frame in frames.iter() {
+                let frame = fit(frame, size);
+
+                let mut re: Vec<f64> = frame.iter().map(|x| *x as f64).collect();
+                let mut im: Vec<f64> = vec![0.0; size];
+                fft(&mut re, &mut im, false);
+
+                for (level, frames) in levels.iter_mut().enumerate() {
+                    // Harmonic h lives in bins h and size - h, the Nyquist bin is always dropped
+                    let harmonics = (size / 2 - 1) >> level;
+                    let (mut level_re, mut level_im) = (re.clone(), im.clone());
+                    for bin in harmonics + 1 ..= size - harmonics - 1 {
+                        level_re[bin] = 0.0;
+                        level_im[bin] = 0.0;
+                    }
+                    fft(&mut level_re, &mut level_im, true);
+                    frames.push(level_re.iter().map(|x| (x / size as f64) as f32).collect());
+                }
+            }
+
+            return Ok(Self { size: size, frame_count: frames.len(), levels: levels });
+        }
+
+        // `frame_count` frames of `size` samples from a function of (frame, phase 0.0 .. 1.0)
+        pub fn from_fn(size: usize, frame_count: usize, function: impl Fn(usize, f64) -> f64) -> Result<Self, NoiseError> {
+            let frames = (0 .. frame_count)
+                .map(|frame| (0 .. size).map(|n| function(frame, n as f64 / size as f64) as f32).collect())
+                .collect();
+            return Self::from_frames(frames);
+        }
+
+        // Sine, triangle, saw and square, to morph through with the position
+        pub fn basic_shapes() -> Self {
+            let table = Self::from_fn(DEFAULT_TABLE_SIZE, 4, |frame, phase| match frame {
+                0 => f64::sin(2.0 * PI * phase),
+                1 => f64::asin(f64::sin(2.0 * PI * phase)) * (2.0 / PI),
+                2 => if phase < 0.5 { 2.0 * phase } else { 2.0 * phase - 2.0 },
+                _ => if phase < 0.5 { 1.0 } else { -1.0 },
+            });
+            return table.unwrap();
+        }
+
+        // Frames stored back to back in a WAV file (first channel only). `frame_size` is the
+        // number of samples per frame, None when the whole file is one cycle.
+        pub fn load_wav(path: &str, frame_size: Option<usize>) -> Result<Self, NoiseError> {
+            let bytes = match fs::read(path) {
+                Ok(bytes) => bytes,
+                Err(e) => return Err(NoiseError::LoadFailed(format!("{}: {}", path, e))),
+            };
+            let samples = match decode_wav(&bytes) {
+                Ok(samples) => samples,
+                Err(reason) => return Err(NoiseError::LoadFailed(format!("{}: {}", path, reason))),
+            };
+
+            let frame_size = frame_size.unwrap_or(samples.len());
+            if frame_size == 0 || samples.len() < frame_size {
+                return Err(NoiseError::LoadFailed(format!("{}: shorter than one frame of {} samples", path, frame_size)));
+            }
+            return Self::from_frames(samples.chunks_exact(frame_size).map(|frame| frame.to_vec()).collect());
+        }
+
+        pub fn size(&self) -> usize {
+            return self.size;
+        }
+
+        pub fn frame_count(&self) -> usize {
+            return self.frame_count;
+        }
+
+        // Most detailed level with no harmonic at or above Nyquist for `frequency`
+        pub fn level_for(&self, frequency: f64, sample_rate: u32) -> usize {
+            let allowed = sample_rate as f64 / 2.0 / f64::abs(frequency).max(1e-9);
+            let mut level = 0;
+            while level + 1 < self.levels.len() && ((self.size / 2 - 1) >> level) as f64 >= allowed {
+                level += 1;
+            }
+            return level;
+        }
+
+        // Value of `frame` at `phase` (0.0 .. 1.0)
+        fn sample(&self, level: usize, frame: usize, phase: f64, interpolation: Interpolation) -> f64 {
+            let table = &self.levels[level][frame];
+            let mask = self.size - 1;
+            let position = phase * self.size as f64;
+            let index = position as usize;
+            let t = position - index as f64;
+
+            let y1 = table[index & mask] as f64;
+            let y2 = table[(index + 1) & mask] as f64;
+            return match interpolation {
+                Interpolation::Linear => y1 + (y2 - y1) * t,
+                Interpolation::Cubic => {
+                    let y0 = table[(index + mask) & mask] as f64;
+                    let y3 = table[(index + 2) & mask] as f64;
+                    cubic(y0, y1, y2, y3, t)
+                }
+            };
+        }
+    }
+
+    // Plays a Wavetable, any number of oscillators can share one
+    #[derive(Clone)]
+    pub struct WavetableOscillator {
+        pub frequency: f64, // Hz
+        pub amplitude: f64,
+        pub position: f64,  // 0.0 (first frame) ..= 1.0 (last frame), frames in between are crossfaded
+        pub interpolation: Interpolation,
+        table: Arc<Wavetable>,
+        phase: f64,
+    }
+
+    impl WavetableOscillator {
+        pub fn new(table: Arc<Wavetable>, frequency: f64, amplitude: f64) -> Self {
+            return Self {
+                frequency: frequency,
+                amplitude: amplitude,
+                position: 0.0,
+                interpolation: Interpolation::Cubic,
+                table: table,
+                phase: 0.0,
+            };
+        }
+
+        // Swaps the table, the phase carries on
+        pub fn set_table(&mut self, table: Arc<Wavetable>) {
+            self.table = table;
+        }
+
+        pub fn reset(&mut self, phase: f64) {
+            self.phase = phase.rem_euclid(1.0);
+        }
+
+        fn value(&self, level: usize) -> f64 {
+            let table = &self.table;
+            let position = self.position.clamp(0.0, 1.0) * (table.frame_count - 1) as f64;
+            let frame = position as usize;
+            let blend = position - frame as f64;
+
+            let a = table.sample(level, frame, self.phase, self.interpolation);
+            if blend == 0.0 {
+                return a;
+            }
+            let b = table.sample(level, frame + 1, self.phase, self.interpolation);
+            return a + (b - a) * blend;
+        }
+
+        pub fn next_sample(&mut self, sample_rate: u32) -> f64 {
+            let level = self.table.level_for(self.frequency, sample_rate);
+            let output = self.amplitude * self.value(level);
+            self.phase = (self.phase + self.frequency / sample_rate as f64).rem_euclid(1.0);
+            return output;
+        }
+
+        // Fills `out` (mono) with the next out.len() samples
+        pub fn render(&mut self, out: &mut [f32], sample_rate: u32) {
+            out.iter_mut().for_each(|sample| *sample = 0.0);
+            self.render_add(out, sample_rate);
+        }
+
+        // Like render() but adds to what is in `out`
+        pub fn render_add(&mut self, out: &mut [f32], sample_rate: u32) {
+            let level = self.table.level_for(self.frequency, sample_rate);
+            let step = self.frequency / sample_rate as f64;
+            for sample in out.iter_mut() {
+                *sample += (self.amplitude * self.value(level)) as f32;
+                self.phase = (self.phase + step).rem_euclid(1.0);
+            }
+        }
+    }
+
+    // Plays the table at the note frequency
+    impl SoundSource for WavetableOscillator {
+        fn generate(&mut self, out: &mut [f32], frequency: f64, sample_rate: u32) {
+            self.frequency = frequency;
+            self.render(out, sample_rate);
+        }
+
+        fn restart(&mut self) {
+            self.phase = 0.0;
+        }
+    }
+
+    // Catmull-Rom between y1 (t = 0) and y2 (t = 1)
+    fn cubic(y0: f64, y1: f64, y2: f64, y3: f64, t: f64) -> f64 {
+        let a = -0.5 * y0 + 1.5 * y1 - 1.5 * y2 + 0.5 * y3;
+        let b = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+        let c = -0.5 * y0 + 0.5 * y2;
+        return ((a * t + b) * t + c) * t + y1;
+    }
+
+    // One cycle to `size` samples, cubic and wrapping around
+    fn resample(frame: &[f32], size: usize) -> Vec<f32> {
+        let length = frame.len();
+        return (0 .. size).map(|n| {
+            let position = n as f64 * length as f64 / size as f64;
+            let index = position as usize;
+            let y = |offset: usize| frame[(index + offset) % length] as f64;
+            cubic(y(length - 1), y(0), y(1), y(2), position - index as f64) as f32
+        }).collect();
+    }
+
+    // One cycle to `size` samples. Longer cycles (a whole file) can hold harmonics `size` samples
+    // cannot, so they are resampled up to a power of two first, cut down to the harmonics below
+    // size / 2 there and only then decimated.
+    fn fit(frame: &[f32], size: usize) -> Vec<f32> {
+        if frame.len() <= size {
+            return if frame.len() == size { frame.to_vec() } else { resample(frame, size) };
+        }
+
+        let large = frame.len().next_power_of_two();
+        let mut re: Vec<f64> = resample(frame, large).iter().map(|x| *x as f64).collect();
+        let mut im: Vec<f64> = vec![0.0; large];
+        fft(&mut re, &mut im, false);
+        let harmonics = size / 2 - 1;
+        for bin in harmonics + 1 ..= large - harmonics - 1 {
+            re[bin] = 0.0;
+            im[bin] = 0.0;
+        }
+        fft(&mut re, &mut im, true);
+
+        let step = large / size;
+        return (0 .. size).map(|n| (re[n * step] / large as f64) as f32).collect();
+    }
+
+    // In place radix-2 FFT, re.len() must be a power of two. The inverse is not scaled.
+    fn fft(re: &mut [f64], im: &mut [f64], inverse: bool) {
+        let n = re.len();
+        let mut j = 0;
+        for i in 1 .. n {
+            let mut bit = n >> 1;
+            while j & bit != 0 {
+                j ^= bit;
+                bit >>= 1;
+            }
+            j |= bit;
+            if i < j {
+                re.swap(i, j);
+                im.swap(i, j);
+            }
+        }
+
+        let sign = if inverse { 1.0 } else { -1.0 };
+        let mut length = 2;
+        while length <= n {
+            let angle = sign * 2.0 * PI / length as f64;
+            for start in (0 .. n).step_by(length) {
+                for k in 0 .. length / 2 {
+                    let (w_re, w_im) = (f64::cos(angle * k as f64), f64::sin(angle * k as f64));
+                    let (a, b) = (start + k, start + k + length / 2);
+                    let t_re = re[b] * w_re - im[b] * w_im;
+                    let t_im = re[b] * w_im + im[b] * w_re;
+                    re[b] = re[a] - t_re;
+                    im[b] = im[a] - t_im;
+                    re[a] += t_re;
+                    im[a] += t_im;
+                }
+            }
+            length <<= 1;
+        }
+    }
+
+    // Samples of the first channel of a RIFF/WAVE file (PCM 16 / 24 / 32 bit or 32 bit float)
+    fn decode_wav(bytes: &[u8]) -> Result<Vec<f32>, String> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err("not a RIFF/WAVE file".to_string());
+        }
+        let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+
+        let mut format: Option<(u16, usize, usize)> = None; // Tag, channels, bits
+        let mut offset = 12;
+        while offset + 8 <= bytes.len() {
+            let id = &bytes[offset .. offset + 4];
+            let length = u32_at(offset + 4) as usize;
+            let body = offset + 8;
+            if body + length > bytes.len() && id != b"data" {
+                return Err("truncated chunk".to_string());
+            }
+
+            if id == b"fmt " && length >= 16 {
+                let mut tag = u16_at(body);
+                if tag == 0xFFFE && length >= 26 {
+                    tag = u16_at(body + 24); // First bytes of the SubFormat GUID
+                }
+                format = Some((tag, u16_at(body + 2) as usize, u16_at(body + 14) as usize));
+            } else if id == b"data" {
+                let (tag, channels, bits) = match format {
+                    Some(format) => format,
+                    None => return Err("data before fmt chunk".to_string()),
+                };
+                let data = &bytes[body .. usize::min(body + length, bytes.len())];
+                let width = bits / 8;
+                if channels == 0 || width == 0 {
+                    return Err("invalid fmt chunk".to_string());
+                }
+                let samples = data.chunks_exact(width * channels).map(|frame| match (tag, bits) {
+                    (1, 16) => Ok(i16::from_le_bytes([frame[0], frame[1]]) as f32 / 32768.0),
+                    (1, 24) => Ok(i32::from_le_bytes([0, frame[0], frame[1], frame[2]]) as f32 / 2147483648.0),
+                    (1, 32) => Ok(i32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]) as f32 / 2147483648.0),
+                    (3, 32) => Ok(f32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]])),
+                    _ => Err(format!("unsupported sample format (tag {}, {} bits)", tag, bits)),
+                });
+                return samples.collect();
+            }
+
+            offset = body + length + (length & 1); // Chunks are word aligned
+        }
+        return Err("no data chunk".to_string());
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::backend::backend::{AudioBackend, BlockSignal, SampleFormat, StreamFormat};
+        use crate::convert::convert;
+        use crate::wav_backend::wav_backend::WavBackend;
+
+        // Harmonic magnitudes 0 ..= size / 2 of one cycle
+        fn spectrum(frame: &[f32]) -> Vec<f64> {
+            let mut re: Vec<f64> = frame.iter().map(|x| *x as f64).collect();
+            let mut im = vec![0.0; frame.len()];
+            fft(&mut re, &mut im, false);
+            return (0 ..= frame.len() / 2).map(|bin| f64::hypot(re[bin], im[bin]) * 2.0 / frame.len() as f64).collect();
+        }
+
+        #[test]
+        fn fft_matches_the_dft() {
+            let signal: Vec<f64> = (0 .. 16).map(|n| f64::sin(n as f64 * 0.7) + (n % 3) as f64).collect();
+            let (mut re, mut im) = (signal.clone(), vec![0.0; 16]);
+            fft(&mut re, &mut im, false);
+            for bin in 0 .. 16 {
+                let angle = |n: usize| -2.0 * PI * (bin * n) as f64 / 16.0;
+                let expected_re: f64 = signal.iter().enumerate().map(|(n, x)| x * f64::cos(angle(n))).sum();
+                let expected_im: f64 = signal.iter().enumerate().map(|(n, x)| x * f64::sin(angle(n))).sum();
+                assert!((re[bin] - expected_re).abs() < 1e-9 && (im[bin] - expected_im).abs() < 1e-9, "bin {}", bin);
+            }
+
+            // The unscaled inverse gives back size times the input
+            fft(&mut re, &mut im, true);
+            for (x, expected) in re.iter().zip(signal.iter()) {
+                assert!((x / 16.0 - expected).abs() < 1e-9);
+            }
+        }
+
+        #[test]
+        fn from_frames_keeps_a_band_limited_frame() {
+            let frame: Vec<f32> = (0 .. 256).map(|n| {
+                let angle = 2.0 * PI * n as f64 / 256.0;
+                (0.6 * f64::sin(angle) + 0.3 * f64::cos(5.0 * angle) + 0.1 * f64::sin(100.0 * angle)) as f32
+            }).collect();
+            let table = Wavetable::from_frames(vec![frame.clone()]).unwrap();
+            assert_eq!(table.size(), 256);
+            for (x, expected) in table.levels[0][0].iter().zip(frame.iter()) {
+                assert!((x - expected).abs() < 1e-6);
+            }
+
+            // Level 1 keeps up to harmonic 63, so harmonic 100 is gone and the others stay
+            let level = spectrum(&table.levels[1][0]);
+            assert!((level[1] - 0.6).abs() < 1e-6 && (level[5] - 0.3).abs() < 1e-6 && level[100] < 1e-6);
+        }
+
+        #[test]
+        fn from_frames_resamples_other_lengths() {
+            let frame: Vec<f32> = (0 .. 600).map(|n| f64::sin(2.0 * PI * n as f64 / 600.0) as f32).collect();
+            let table = Wavetable::from_frames(vec![frame]).unwrap();
+            assert_eq!(table.size(), DEFAULT_TABLE_SIZE);
+            for (n, x) in table.levels[0][0].iter().enumerate() {
+                assert!((*x as f64 - f64::sin(2.0 * PI * n as f64 / DEFAULT_TABLE_SIZE as f64)).abs() < 1e-3);
+            }
+        }
+
+        #[test]
+        fn from_frames_band_limits_long_cycles() {
+            // Harmonic 1500 fits 5000 samples but not the 2048 of the table, where it would fold to 548
+            let frame: Vec<f32> = (0 .. 5000).map(|n| {
+                let angle = 2.0 * PI * n as f64 / 5000.0;
+                (0.5 * f64::sin(angle) + 0.5 * f64::sin(1500.0 * angle)) as f32
+            }).collect();
+            let table = Wavetable::from_frames(vec![frame]).unwrap();
+            assert_eq!(table.size(), DEFAULT_TABLE_SIZE);
+
+            let level = spectrum(&table.levels[0][0]);
+            assert!((level[1] - 0.5).abs() < 1e-3, "fundamental {}", level[1]);
+            assert!(level[548] < 1e-4, "aliased harmonic {}", level[548]);
+        }
+
+        #[test]
+        fn levels_have_nothing_above_their_limit() {
+            let table = Wavetable::basic_shapes();
+            for (level, frames) in table.levels.iter().enumerate() {
+                let harmonics = (table.size() / 2 - 1) >> level;
+                for frame in frames.iter() {
+                    let spectrum = spectrum(frame);
+                    assert!(spectrum[harmonics + 1 ..].iter().all(|magnitude| *magnitude < 1e-6), "level {}", level);
+                }
+            }
+            // The last level is a pure fundamental
+            assert_eq!((table.size() / 2 - 1) >> (table.levels.len() - 1), 1);
+        }
+
+        #[test]
+        fn level_for_stays_below_nyquist() {
+            let table = Wavetable::basic_shapes();
+            let sample_rate = 44100;
+            let harmonics = |level: usize| ((table.size() / 2 - 1) >> level) as f64;
+            let mut frequency = 20.0;
+            while frequency < 20000.0 {
+                let level = table.level_for(frequency, sample_rate);
+                assert!(harmonics(level) * frequency < sample_rate as f64 / 2.0, "{}Hz plays level {}", frequency, level);
+                // and it is the most detailed level that does
+                assert!(level == 0 || harmonics(level - 1) * frequency >= sample_rate as f64 / 2.0, "{}Hz could play level {}", frequency, level - 1);
+                frequency *= 1.07;
+            }
+        }
+
+        // Written by WavBackend, plain PCM as well as WAVE_FORMAT_EXTENSIBLE with a fact chunk
+        #[test]
+        fn decode_wav_reads_what_wav_backend_writes() {
+            let frame: Vec<f32> = (0 .. 64).map(|n| f64::sin(2.0 * PI * n as f64 / 64.0) as f32 * 0.5).collect();
+            for (sample_format, channels) in [(SampleFormat::I16, 1), (SampleFormat::I24, 2), (SampleFormat::I32, 1), (SampleFormat::F32, 2)].iter() {
+                let path = std::env::temp_dir().join(format!("wavetable-test-{}-{}.wav", sample_format.name(), channels));
+                let path = path.to_str().unwrap();
+                let interleaved: Vec<f32> = frame.iter().flat_map(|x| vec![*x; *channels as usize]).collect();
+                let mut bytes = vec![0u8; interleaved.len() * sample_format.bytes()];
+                convert::encode(*sample_format, &interleaved, &mut bytes);
+
+                let format = StreamFormat { sample_rate: 44100, channels: *channels, blocks: 1, block_samples: interleaved.len() as u32, sample_format: *sample_format };
+                let mut backend = WavBackend::new(path);
+                backend.open(0, format, Arc::new(BlockSignal::new(1))).unwrap();
+                backend.submit(0, &bytes);
+                backend.close();
+
+                let decoded = decode_wav(&fs::read(path).unwrap()).unwrap();
+                let _ = fs::remove_file(path);
+                assert_eq!(decoded.len(), frame.len(), "{}", sample_format.name());
+                for (x, expected) in decoded.iter().zip(frame.iter()) {
+                    assert!((x - expected).abs() < 1e-4, "{}", sample_format.name());
+                }
+            }
+        }
+
+        #[test]
+        fn decode_wav_rejects_other_files() {
+            assert!(decode_wav(b"not a wav file at all").is_err());
+            assert!(decode_wav(b"RIFF\x04\x00\x00\x00WAVE").is_err());
+        }
+    }
+}
@@ -0,0 +1,227 @@
+pub mod winmm_backend {
+
+    extern crate winapi;
+
+    use crate::backend::backend::{AudioBackend, BlockSignal, DeviceInfo, SampleFormat, StreamFormat};
+    use crate::error::error::NoiseError;
+
+    use std::mem;
+    use std::ptr;
+    use std::sync::Arc;
+
+    use winapi::shared::basetsd;
+    use winapi::shared::ksmedia;
+    use winapi::shared::minwindef;
+    use winapi::shared::mmreg;
+    use winapi::shared::ntdef;
+    use winapi::um::mmeapi;
+    use winapi::um::mmsystem;
+
+    const WHDR_PREPARED: minwindef::DWORD = 0x00000002;
+
+    // 16 bit mono / stereo WAVEOUTCAPS format flags per sample rate
+    const CAPS_RATES: [(u32, minwindef::DWORD, minwindef::DWORD); 5] = [
+        (11025, mmsystem::WAVE_FORMAT_1M16, mmsystem::WAVE_FORMAT_1S16),
+        (22050, mmsystem::WAVE_FORMAT_2M16, mmsystem::WAVE_FORMAT_2S16),
+        (44100, mmsystem::WAVE_FORMAT_4M16, mmsystem::WAVE_FORMAT_4S16),
+        (48000, mmsystem::WAVE_FORMAT_48M16, mmsystem::WAVE_FORMAT_48S16),
+        (96000, mmsystem::WAVE_FORMAT_96M16, mmsystem::WAVE_FORMAT_96S16),
+    ];
+
+    pub struct WinMmBackend {
+        block_bytes: usize,
+        block_memory: Vec<u8>,
+
+        wave_headers: Vec<mmsystem::WAVEHDR>, // Array of headers
+        hw_device: mmsystem::HWAVEOUT,        // Chosen device
+
+        signal: Option<Arc<BlockSignal>>,
+    }
+
+    // The device handle and header pointers are only touched from the thread owning the backend,
+    // the winmm callback only sees the BlockSignal.
+    unsafe impl Send for WinMmBackend {}
+
+    impl WinMmBackend {
+        pub fn new() -> Self {
+            return Self {
+                block_bytes: 0,
+                block_memory: Vec::<u8>::new(),
+                wave_headers: Vec::<mmsystem::WAVEHDR>::new(),
+                hw_device: ptr::null_mut(),
+                signal: None,
+            };
+        }
+
+        // Plain WAVEFORMATEX only covers 8/16 bit PCM in mono or stereo, everything else
+        // goes through WAVEFORMATEXTENSIBLE with the matching KSDATAFORMAT subtype
+        fn wave_format(format: &StreamFormat) -> mmreg::WAVEFORMATEXTENSIBLE {
+            unsafe {
+                let sample_format = format.sample_format;
+                let mut wave_format: mmreg::WAVEFORMATEXTENSIBLE = mem::zeroed::<mmreg::WAVEFORMATEXTENSIBLE>();
+                wave_format.Format.nSamplesPerSec = format.sample_rate;
+                wave_format.Format.wBitsPerSample = sample_format.bits();
+                wave_format.Format.nChannels = format.channels as u16;
+                wave_format.Format.nBlockAlign = (wave_format.Format.wBitsPerSample / 8) * wave_format.Format.nChannels;
+                wave_format.Format.nAvgBytesPerSec = wave_format.Format.nSamplesPerSec * wave_format.Format.nBlockAlign as u32;
+
+                if sample_format == SampleFormat::I16 && format.channels <= 2 {
+                    wave_format.Format.wFormatTag = mmreg::WAVE_FORMAT_PCM;
+                    wave_format.Format.cbSize = 0;
+                } else {
+                    wave_format.Format.wFormatTag = mmreg::WAVE_FORMAT_EXTENSIBLE;
+                    wave_format.Format.cbSize = (mem::size_of::<mmreg::WAVEFORMATEXTENSIBLE>() - mem::size_of::<mmreg::WAVEFORMATEX>()) as u16;
+                    wave_format.Samples = sample_format.bits(); // wValidBitsPerSample
+                    wave_format.dwChannelMask = if format.channels < 32 { (1 << format.channels) - 1 } else { 0 };
+                    wave_format.SubFormat = if sample_format.is_float() { ksmedia::KSDATAFORMAT_SUBTYPE_IEEE_FLOAT } else { ksmedia::KSDATAFORMAT_SUBTYPE_PCM };
+                }
+                return wave_format;
+            }
+        }
+
+        unsafe extern "system" fn wave_out_proc(
+            _wave_out: mmsystem::HWAVEOUT,
+            msg: minwindef::UINT,
+            dw_instance: basetsd::DWORD_PTR,
+            _dw_param1: basetsd::DWORD_PTR,
+            _dw_param2: basetsd::DWORD_PTR,
+        ) {
+            if msg != mmsystem::WOM_DONE {
+                return;
+            }
+            let signal = dw_instance as *const BlockSignal;
+            (*signal).block_done();
+        }
+    }
+
+    impl AudioBackend for WinMmBackend {
+        fn enumerate(&self) -> Vec<DeviceInfo> {
+            let mut devices: Vec<DeviceInfo> = Vec::new();
+            unsafe {
+                let device_count: u32 = mmeapi::waveOutGetNumDevs();
+                let mut woc: mmsystem::WAVEOUTCAPSW = mem::zeroed::<mmsystem::WAVEOUTCAPSW>();
+
+                for n in 0..device_count {
+                    if mmeapi::waveOutGetDevCapsW(
+                        n as usize,
+                        &mut woc,
+                        mem::size_of::<mmsystem::WAVEOUTCAPSW>() as u32,
+                    ) == mmsystem::MMSYSERR_NOERROR
+                    {
+                        // szPname is a fixed size, NUL padded buffer
+                        let name_len = woc.szPname.iter().position(|c| *c == 0).unwrap_or(woc.szPname.len());
+                        let name = String::from_utf16_lossy(&woc.szPname[..name_len]);
+                        let formats = woc.dwFormats;
+                        let max_channels = woc.wChannels as u32;
+
+                        let sample_rates = CAPS_RATES.iter()
+                            .filter(|(_, mono, stereo)| formats & (mono | stereo) != 0)
+                            .map(|(rate, _, _)| *rate)
+                            .collect();
+                        devices.push(DeviceInfo {
+                            id: n as usize,
+                            name: name,
+                            backend: "winmm",
+                            sample_rates: sample_rates,
+                            channels: (1..=max_channels).collect(),
+                            // Not reported by the caps, an unsupported one fails in open() with WAVERR_BADFORMAT
+                            sample_formats: vec![SampleFormat::I16, SampleFormat::I24, SampleFormat::I32, SampleFormat::F32],
+                            // winmm has no notion of a default device besides the mapper, the first one is used
+                            is_default: n == 0,
+                            probed: true,
+                        });
+                    }
+                }
+            }
+            return devices;
+        }
+
+        fn open(&mut self, device_id: usize, format: StreamFormat, signal: Arc<BlockSignal>) -> Result<(), NoiseError> {
+            unsafe {
+                eprintln!("Initializing wave_format");
+                let wave_format = Self::wave_format(&format);
+
+                // Open Device if valid, the callback gets the block signal as its instance data
+                eprintln!("Opening device (waveOutOpen)");
+                let instance = Arc::as_ptr(&signal) as basetsd::DWORD_PTR;
+                let callback_func_ptr = Self::wave_out_proc as basetsd::DWORD_PTR;
+                let result = mmeapi::waveOutOpen(&mut self.hw_device, device_id as u32, &wave_format.Format, callback_func_ptr,
+                    instance, mmsystem::CALLBACK_FUNCTION);
+                if result != mmsystem::MMSYSERR_NOERROR {
+                    self.hw_device = ptr::null_mut();
+                    return Err(match result {
+                        mmsystem::MMSYSERR_BADDEVICEID => NoiseError::DeviceNotFound(format!("#{}", device_id)),
+                        mmsystem::WAVERR_BADFORMAT => NoiseError::FormatUnsupported(format!("{}Hz {} channels {}", format.sample_rate, format.channels, format.sample_format.name())),
+                        _ => NoiseError::OpenFailed(result as i32),
+                    });
+                }
+                self.signal = Some(signal);
+
+                // Allocate Wave | Block memory
+                self.block_bytes = format.block_samples as usize * format.sample_format.bytes();
+                self.block_memory = vec![0; format.blocks as usize * self.block_bytes];
+                self.wave_headers = vec![mem::zeroed(); format.blocks as usize];
+
+                // Link headers to block memory
+                for n in 0..format.blocks as usize {
+                    let offset = n * self.block_bytes;
+                    self.wave_headers[n].dwBufferLength = self.block_bytes as u32;
+                    self.wave_headers[n].lpData = self.block_memory.as_mut_ptr().add(offset) as ntdef::LPSTR;
+                }
+            }
+            eprintln!("Wave format initialized!");
+            return Ok(());
+        }
+
+        fn submit(&mut self, block: usize, samples: &[u8]) {
+            let header_size = mem::size_of::<mmsystem::WAVEHDR>() as u32;
+            unsafe {
+                // Header still prepared from its previous trip through the device
+                if self.wave_headers[block].dwFlags & WHDR_PREPARED != 0 {
+                    mmeapi::waveOutUnprepareHeader(self.hw_device, &mut self.wave_headers[block], header_size);
+                }
+
+                let offset = block * self.block_bytes;
+                self.block_memory[offset..offset + samples.len()].copy_from_slice(samples);
+
+                // Send block to sound devices
+                mmeapi::waveOutPrepareHeader(self.hw_device, &mut self.wave_headers[block], header_size);
+                mmeapi::waveOutWrite(self.hw_device, &mut self.wave_headers[block], header_size);
+            }
+        }
+
+        fn close(&mut self) {
+            if self.hw_device.is_null() {
+                return;
+            }
+            let header_size = mem::size_of::<mmsystem::WAVEHDR>() as u32;
+            unsafe {
+                mmeapi::waveOutReset(self.hw_device);
+                for header in self.wave_headers.iter_mut() {
+                    if header.dwFlags & WHDR_PREPARED != 0 {
+                        mmeapi::waveOutUnprepareHeader(self.hw_device, header, header_size);
+                    }
+                }
+                mmeapi::waveOutClose(self.hw_device);
+            }
+            self.hw_device = ptr::null_mut();
+            self.signal = None;
+        }
+
+        fn pause(&mut self) {
+            if !self.hw_device.is_null() {
+                unsafe { mmeapi::waveOutPause(self.hw_device); }
+            }
+        }
+
+        fn resume(&mut self) {
+            if !self.hw_device.is_null() {
+                unsafe { mmeapi::waveOutRestart(self.hw_device); }
+            }
+        }
+    }
+
+    impl Drop for WinMmBackend {
+        fn drop(&mut self) { self.close(); }
+    }
+}